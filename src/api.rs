@@ -0,0 +1,210 @@
+//! A local REST API, with the same operations as the MQTT entities, for anything that doesn't speak MQTT. It is served on the HTTP listener, and works on the same cec-client process as everything else.
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::bridge::{command_tv, tv_schema};
+use crate::cec_diagnostics::CecDevice;
+use crate::cec_frame::{CecFrame, Direction, UserControl};
+use crate::error::BridgeError;
+use crate::hdmicec_entity::HdmiCecProcess;
+
+type Cec = State<Arc<HdmiCecProcess>>;
+
+#[derive(Debug, Serialize)]
+struct StateResponse {
+    /// the TV's power state, as "ON", "OFF" or "UNKNOWN".
+    power: Option<String>,
+    /// the physical address of the active source, like "1.0.0.0".
+    active_source: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyRequest {
+    /// a key like "select" or "volume_up".
+    key: String,
+    /// the logical address of the device to send the key to. This is the TV (0) by default.
+    #[serde(default)]
+    destination: u8,
+}
+
+#[derive(Debug, Deserialize)]
+struct FrameRequest {
+    /// colon separated hex bytes, like "10:8f".
+    frame: String,
+}
+
+/// The API routes. 'control' adds the ones that send anything on the bus, which would do nothing in monitor mode.
+pub fn router(hdmicec: Arc<HdmiCecProcess>, control: bool) -> Router {
+    let mut router = Router::new()
+        .route("/api/state", get(current_state))
+        .route("/api/devices", get(devices));
+    if control {
+        router = router
+            .route("/api/tv", post(tv))
+            .route("/api/volume_up", post(volume_up))
+            .route("/api/volume_down", post(volume_down))
+            .route("/api/mute", post(mute))
+            .route("/api/source/{index}", post(source))
+            .route("/api/key", post(key))
+            .route("/api/tx", post(transmit));
+    }
+    return router.with_state(hdmicec);
+}
+
+/// 204 if it worked, or the error with a status code to match. Either way, event listeners hear about it like any other command.
+fn respond(hdmicec: &HdmiCecProcess, entity: &str, result: Result<(), BridgeError>) -> Response {
+    hdmicec.report_command(entity, &result);
+    return match result {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(err @ BridgeError::ProcessStopped) => {
+            (StatusCode::SERVICE_UNAVAILABLE, err.to_string()).into_response()
+        }
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+}
+
+fn invalid(entity: &str, payload: &str) -> BridgeError {
+    return BridgeError::InvalidCommand {
+        entity: entity.to_string(),
+        payload: payload.to_string(),
+    };
+}
+
+async fn current_state(State(hdmicec): Cec) -> Json<StateResponse> {
+    return Json(StateResponse {
+        power: hdmicec.tv_state(),
+        active_source: hdmicec.active_source(),
+    });
+}
+
+/// every other device we have heard from on the bus.
+async fn devices(State(hdmicec): Cec) -> Json<Vec<CecDevice>> {
+    let diagnostics = hdmicec.diagnostics();
+    let devices = diagnostics.borrow().devices.values().cloned().collect();
+    return Json(devices);
+}
+
+/// the same commands the TV switch takes over MQTT, like "ON", or {"source":"HDMI 2","power":"ON"}.
+async fn tv(State(hdmicec): Cec, body: String) -> Response {
+    let schema = tv_schema();
+    return respond(
+        &hdmicec,
+        "tv",
+        schema
+            .parse("tv", &body)
+            .and_then(|command| command_tv(&hdmicec, &schema, &command)),
+    );
+}
+
+async fn volume_up(State(hdmicec): Cec) -> Response {
+    return respond(&hdmicec, "volume_up", hdmicec.volume_up());
+}
+
+async fn volume_down(State(hdmicec): Cec) -> Response {
+    return respond(&hdmicec, "volume_down", hdmicec.volume_down());
+}
+
+async fn mute(State(hdmicec): Cec) -> Response {
+    return respond(&hdmicec, "mute", hdmicec.mute());
+}
+
+/// switch to a source by number, just like the source buttons.
+async fn source(State(hdmicec): Cec, Path(index): Path<usize>) -> Response {
+    if tv_schema().sources.get(index.wrapping_sub(1)).is_none() {
+        return respond(
+            &hdmicec,
+            "source",
+            Err(invalid("source", &index.to_string())),
+        );
+    }
+    return respond(&hdmicec, "source", hdmicec.set_active_source(index));
+}
+
+async fn key(State(hdmicec): Cec, Json(request): Json<KeyRequest>) -> Response {
+    return respond(
+        &hdmicec,
+        "key",
+        request
+            .key
+            .parse::<UserControl>()
+            .map_err(|_| invalid("key", &request.key))
+            .and_then(|key| hdmicec.press_key(request.destination, key)),
+    );
+}
+
+async fn transmit(State(hdmicec): Cec, Json(request): Json<FrameRequest>) -> Response {
+    return respond(
+        &hdmicec,
+        "tx",
+        CecFrame::parse(&request.frame, Direction::Sent)
+            .ok_or_else(|| invalid("tx", &request.frame))
+            .and_then(|frame| hdmicec.transmit(&frame)),
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn controlling_the_tv_over_http() {
+    use crate::config::{CecConfig, SimulatorConfig};
+    use crate::http::{request, serve};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    let hdmicec = Arc::new(
+        HdmiCecProcess::new(&CecConfig {
+            simulator: Some(SimulatorConfig::default()),
+            ..CecConfig::default()
+        })
+        .expect("could not start simulator"),
+    );
+    hdmicec.listen().expect("could not listen");
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("could not bind");
+    let address = listener.local_addr().expect("no local address").to_string();
+    tokio::spawn(serve(listener, router(hdmicec.clone(), true)));
+
+    let post = |path: &'static str, body: &'static str| {
+        let address = address.clone();
+        async move {
+            request(&address, "POST", path, body)
+                .await
+                .expect("request failed")
+        }
+    };
+    assert_eq!(post("/api/tv", r#"{"power":"ON"}"#).await.0, 204);
+    assert_eq!(post("/api/tv", "SIDEWAYS").await.0, 400);
+    assert_eq!(post("/api/source/2", "").await.0, 204);
+    assert_eq!(post("/api/source/9", "").await.0, 400);
+    assert_eq!(post("/api/key", r#"{"key":"select"}"#).await.0, 204);
+    assert_eq!(post("/api/key", r#"{"key":"self_destruct"}"#).await.0, 400);
+    assert_eq!(post("/api/tx", r#"{"frame":"zz"}"#).await.0, 400);
+    // ask the soundbar for its physical address, so it shows up in the inventory.
+    assert_eq!(post("/api/tx", r#"{"frame":"15:83"}"#).await.0, 204);
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let (status, body) = request(&address, "GET", "/api/state", "")
+        .await
+        .expect("request failed");
+    assert_eq!(status, 200);
+    let state: serde_json::Value = serde_json::from_str(&body).expect("state is not json");
+    assert_eq!(state["power"], "ON");
+    assert_eq!(state["active_source"], "2.0.0.0");
+
+    let (_status, body) = request(&address, "GET", "/api/devices", "")
+        .await
+        .expect("request failed");
+    let devices: serde_json::Value = serde_json::from_str(&body).expect("devices is not json");
+    assert!(devices
+        .as_array()
+        .expect("devices is not a list")
+        .iter()
+        .any(|device| device["name"] == "Audio" && device["physical_address"] == "1.0.0.0"));
+}
@@ -0,0 +1,198 @@
+use anyhow::{anyhow, Error};
+use clap::{Parser, Subcommand};
+use log::info;
+
+use crate::cli::CliCommand;
+use crate::config::{Config, SimulatorConfig};
+
+const CONFIG_FILE: &str = "config.toml";
+
+/// Bridge HDMI-CEC devices, like your TV, to homeassistant over MQTT.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// The config file, the same as --config. This is how it was always given, like `hdmicec2mqtt config.toml`.
+    #[arg(global = true, value_name = "CONFIG")]
+    config_file: Option<String>,
+
+    /// The config file.
+    #[arg(short, long, global = true, default_value = CONFIG_FILE)]
+    config: String,
+
+    /// Only log messages at this level or above, like "debug". This takes the same filters as RUST_LOG, and overrides it.
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+
+    /// Talk to a simulated CEC bus instead of starting cec-client, so nothing is sent to the real devices. This is for the commands that only use the bus, like `power on`: the bridge and purge would still change homeassistant's entities over MQTT, so they refuse to run with it.
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Override the MQTT broker's host from the config file.
+    #[arg(long, global = true)]
+    mqtt_host: Option<String>,
+
+    /// Override the MQTT broker's port from the config file.
+    #[arg(long, global = true)]
+    mqtt_port: Option<u16>,
+
+    /// Override the device's unique_id from the config file.
+    #[arg(long, global = true)]
+    unique_id: Option<String>,
+
+    /// Override the discovery topic prefix from the config file.
+    #[arg(long, global = true)]
+    topic_prefix: Option<String>,
+
+    /// Override the CEC adapter port from the config file, like "/dev/cec0".
+    #[arg(long, global = true)]
+    cec_port: Option<String>,
+
+    /// Check that the config file is valid, and exit.
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Print the discovery messages that would be published to homeassistant, and exit.
+    #[arg(long)]
+    pub print_discovery: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Remove everything we ever added to homeassistant.
+    Purge,
+    /// Ask a running bridge how it is doing, and fail if it is unhealthy.
+    Healthcheck,
+    #[command(flatten)]
+    Cec(CliCommand),
+}
+
+impl Args {
+    /// the config file to use. The positional one wins over --config, because --config always has its default.
+    pub fn config_path(&self) -> &str {
+        return self.config_file.as_deref().unwrap_or(&self.config);
+    }
+
+    /// read the config file, with the overrides from the environment, and then the command line. Anything that wouldn't work is reported all at once.
+    pub fn load_config(&self) -> Result<Config, Error> {
+        let path = self.config_path();
+        info!("Reading config file at {path}");
+        let mut config = Config::load(path)?;
+        self.apply_overrides(&mut config);
+        let problems = config.validate();
+        if !problems.is_empty() {
+            let problems: Vec<String> =
+                problems.iter().map(|problem| problem.to_string()).collect();
+            return Err(anyhow!("invalid config:\n  {}", problems.join("\n  ")));
+        }
+        return Ok(config);
+    }
+
+    /// a dry run only simulates the CEC bus, so refuse the commands that would still publish to the real MQTT broker.
+    pub fn check_dry_run(&self) -> Result<(), Error> {
+        if !self.dry_run || self.check_config || self.print_discovery {
+            return Ok(());
+        }
+        return match self.command {
+            None | Some(Command::Purge) => Err(anyhow!(
+                "--dry-run only simulates the CEC bus, and this would still publish to the MQTT broker. use --print-discovery to see what the bridge would announce"
+            )),
+            Some(Command::Healthcheck) | Some(Command::Cec(_)) => Ok(()),
+        };
+    }
+
+    fn apply_overrides(&self, config: &mut Config) {
+        self.mqtt_host.as_ref().map(|host| {
+            config.mqtt.host = host.clone();
+        });
+        self.mqtt_port.map(|port| {
+            config.mqtt.port = port;
+        });
+        self.unique_id.as_ref().map(|unique_id| {
+            config.device.unique_id = unique_id.clone();
+        });
+        self.topic_prefix.as_ref().map(|prefix| {
+            config.topic.prefix = prefix.clone();
+        });
+        self.cec_port.as_ref().map(|port| {
+            config.cec.port = Some(port.clone());
+        });
+        // a replay doesn't touch the bus either, so that can stay.
+        if self.dry_run && config.cec.replay.is_none() {
+            info!("dry run: using a simulated CEC bus instead of cec-client");
+            config
+                .cec
+                .simulator
+                .get_or_insert_with(SimulatorConfig::default);
+        }
+    }
+}
+
+#[test]
+fn parsing_args() {
+    use crate::cli::PowerArgument;
+
+    let args = Args::try_parse_from(["hdmicec2mqtt", "/config.toml"]).expect("invalid args");
+    assert!(args.command.is_none());
+    assert_eq!(args.config_path(), "/config.toml");
+
+    let args = Args::try_parse_from(["hdmicec2mqtt", "purge", "my.toml"]).expect("invalid args");
+    assert!(matches!(args.command, Some(Command::Purge)));
+    assert_eq!(args.config_path(), "my.toml");
+
+    let args = Args::try_parse_from(["hdmicec2mqtt", "power", "on", "--config", "my.toml"])
+        .expect("invalid args");
+    assert!(matches!(
+        args.command,
+        Some(Command::Cec(CliCommand::Power {
+            state: PowerArgument::On
+        }))
+    ));
+    assert_eq!(args.config_path(), "my.toml");
+
+    let args = Args::try_parse_from(["hdmicec2mqtt", "--check-config"]).expect("invalid args");
+    assert!(args.check_config);
+    assert_eq!(args.config_path(), CONFIG_FILE);
+
+    let args = Args::try_parse_from(["hdmicec2mqtt", "--dry-run"]).expect("invalid args");
+    assert!(args.check_dry_run().is_err());
+    let args =
+        Args::try_parse_from(["hdmicec2mqtt", "--dry-run", "power", "on"]).expect("invalid args");
+    assert!(args.check_dry_run().is_ok());
+
+    assert!(Args::try_parse_from(["hdmicec2mqtt", "volume", "sideways"]).is_err());
+    assert!(Args::try_parse_from(["hdmicec2mqtt", "send"]).is_err());
+    assert!(Args::try_parse_from(["hdmicec2mqtt", "--mqtt-port", "lots"]).is_err());
+}
+
+#[test]
+fn overriding_the_config() {
+    let args = Args::try_parse_from([
+        "hdmicec2mqtt",
+        "--mqtt-host",
+        "broker.lan",
+        "--mqtt-port",
+        "8883",
+        "--topic-prefix",
+        "ha",
+        "--dry-run",
+    ])
+    .expect("invalid args");
+    let mut config: Config = toml::from_str(
+        r#"
+        [mqtt]
+        host = "localhost"
+        port = 1883
+        [topic]
+        [device]
+        "#,
+    )
+    .expect("invalid config");
+    args.apply_overrides(&mut config);
+    assert_eq!(config.mqtt.host, "broker.lan");
+    assert_eq!(config.mqtt.port, 8883);
+    assert_eq!(config.topic.prefix, "ha");
+    assert!(config.cec.simulator.is_some());
+}
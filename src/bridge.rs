@@ -0,0 +1,400 @@
+use anyhow::Error;
+use log::{debug, error, info, warn};
+use std::{future::Future, sync::Arc, time::Duration};
+use tokio::sync::mpsc;
+
+use crate::cec_diagnostics::CecDiagnostics;
+use crate::command::{Command, CommandSchema, PowerCommand};
+use crate::config::{Config, MacroConfig, MacroPlatform, SimulatorConfig};
+use crate::device_class::{BinarySensorClass, SwitchClass};
+use crate::error::BridgeError;
+use crate::ha_entity::{Device, Entity, EntityClass, SimpleCommand};
+use crate::hdmicec_entity::{CecEvent, ClonableHdmiCecProcess, HdmiCecProcess};
+use crate::health::Health;
+use crate::macros::MacroRunner;
+use crate::payloads::{EntityCategory, StateClass};
+use crate::service::{discovery_messages, HaBroker};
+
+/// Start up cec-client and the mqtt client from a config, and connect them together. This runs until 'shutdown' completes, or the mqtt connection fails. Every config sent on 'reloads' replaces the running one, see HaBroker::listen().
+pub async fn run(
+    config: Config,
+    shutdown: impl Future<Output = ()>,
+    reloads: mpsc::UnboundedReceiver<Config>,
+) -> Result<(), Error> {
+    //start up the cec-client process. We will share this in a few different
+    // tasks, so we'll wrap it in a Arc so we can clone it.
+    let hdmicec = Arc::new(HdmiCecProcess::new(&config.cec)?);
+    let entities = all_entities(&config, &hdmicec);
+
+    // start up the mqtt client, and attach all our entities.
+    // then, start listening for mqtt messages, and output from
+    // cec-client.
+    // (note that homeassistant.listen() only returns on connection errors, so we race it against the shutdown signal.)
+    let http_config = config.http.clone();
+    let monitor = config.cec.monitor;
+    let mut homeassistant = HaBroker::from_config(config);
+    let http = match http_config {
+        Some(http_config) => {
+            // we never poll the TV in monitor mode, so there is no poll to be late.
+            let max_poll_age = Duration::from_secs_f64(http_config.max_poll_age);
+            let health = Health::new(
+                homeassistant.connected(),
+                hdmicec.diagnostics(),
+                (!monitor).then_some(max_poll_age),
+            );
+            let mut router =
+                crate::http::router(health).merge(crate::websocket::router(hdmicec.clone()));
+            if http_config.api {
+                router = router.merge(crate::api::router(hdmicec.clone(), !monitor));
+            }
+            Some(crate::http::start(&http_config, router).await?)
+        }
+        None => None,
+    };
+    for entity in entities {
+        homeassistant.add_entity(entity).await;
+    }
+    homeassistant.restore().await;
+    hdmicec.listen()?;
+    let result = tokio::select! {
+        result = homeassistant.listen(reloads, |config| {
+            // the old entities' listeners would keep publishing to their topics otherwise.
+            hdmicec.detach_listeners();
+            all_entities(config, &hdmicec)
+        }) => result,
+        _ = shutdown => {
+            info!("shutting down...");
+            Ok(())
+        },
+    };
+
+    // half the time for each, so we are done before whoever asked us to stop loses patience.
+    let timeout = Duration::from_secs_f64(homeassistant.config().shutdown.timeout);
+    if tokio::time::timeout(timeout / 2, homeassistant.shutdown())
+        .await
+        .is_err()
+    {
+        warn!("timed out disconnecting from mqtt");
+    }
+    if let Err(err) = hdmicec.quit(timeout / 2).await {
+        error!("could not stop cec-client: {err}");
+    }
+    http.map(|http| http.abort());
+    return result;
+}
+
+/// Remove every entity we ever announced from homeassistant, and everything else we left on the broker. cec-client is never started.
+pub async fn purge(config: Config) -> Result<(), Error> {
+    info!("purging everything we added to homeassistant...");
+    HaBroker::from_config(config).purge().await;
+    return Ok(());
+}
+
+/// Every entity we add to homeassistant, for a config.
+fn all_entities(config: &Config, hdmicec: &Arc<HdmiCecProcess>) -> Vec<Entity> {
+    // Every entity should be part of a "Device" for homeassistant.
+    let device = Device::from_config(config);
+
+    // in monitor mode, we can only watch the bus, so only read-only sensors make sense.
+    let mut entities = if config.cec.monitor {
+        info!("cec-client is in monitor mode. Only sensors will be added.");
+        monitor_entities(&device, hdmicec)
+    } else {
+        let mut entities = control_entities(&device, hdmicec);
+        entities.extend(macro_entities(&device, hdmicec, &config.macros));
+        entities
+    };
+    entities.extend(diagnostic_entities(&device, hdmicec));
+    return entities;
+}
+
+/// Print the discovery messages run() would publish, without connecting to anything. The entities are built on a simulated bus, so cec-client isn't started either.
+pub async fn print_discovery(mut config: Config) -> Result<(), Error> {
+    config.cec.replay = None;
+    config.cec.simulator = Some(SimulatorConfig::default());
+    let hdmicec = Arc::new(HdmiCecProcess::new(&config.cec)?);
+    let entities = all_entities(&config, &hdmicec);
+    for (topic, payload) in discovery_messages(&config, &entities, &[]) {
+        let payload: serde_json::Value = serde_json::from_str(&payload)?;
+        println!("{topic}\n{payload:#}\n");
+    }
+    return Ok(());
+}
+
+/// The entities for controlling the TV: power, volume, and input sources.
+pub fn control_entities(device: &Device, hdmicec: &Arc<HdmiCecProcess>) -> Vec<Entity> {
+    let switch_hdmicec = hdmicec.clone(); // clone so we can move into a closure later.
+    let restore_hdmicec = hdmicec.clone();
+
+    // Setup a "switch" device for the TV's power state. It also takes JSON commands for switching sources, like {"source":"HDMI 2","power":"ON"}.
+    let schema = tv_schema();
+    let switch = device
+        .entity("tv", EntityClass::Switch(Some(SwitchClass::Switch)))
+        .with_icon("mdi:television")
+        .with_schema(schema.clone())
+        .with_restore(move |state| {
+            restore_hdmicec.restore_tv_state(state);
+        })
+        .with_state(move |state| {
+            switch_hdmicec.attach_statemanager(state.clone());
+
+            // make another clone for the next closure...
+            let poll_hdmicec = switch_hdmicec.clone();
+            switch_hdmicec.spawn_listener(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(10));
+                loop {
+                    interval.tick().await;
+                    debug!("querying TV...");
+                    if let Err(err) = poll_hdmicec.query_tv_state() {
+                        error!("could not query the TV: {err}");
+                    }
+                }
+            });
+        })
+        .with_commands(hdmicec.command("tv", move |hdmicec, command| {
+            return command_tv(hdmicec, &schema, command);
+        }));
+
+    // Setup a simple button for turning the volume up
+    let vol_up = device
+        .entity("volumeup", EntityClass::Button(None))
+        .with_icon("mdi:volume-plus")
+        .with_commands(hdmicec.command("volumeup", |hdmicec, _command| {
+            info!("Volume Up");
+            return hdmicec.volume_up();
+        }));
+
+    // Setup a simple button for turning the volume down
+    let vol_down = device
+        .entity("volumedown", EntityClass::Button(None))
+        .with_icon("mdi:volume-minus")
+        .with_commands(hdmicec.command("volumedown", |hdmicec, _command| {
+            info!("Volume Down");
+            return hdmicec.volume_down();
+        }));
+
+    // Setup a simple button for muting
+    let mute = device
+        .entity("mute", EntityClass::Button(None))
+        .with_icon("mdi:volume-mute")
+        .with_commands(hdmicec.command("mute", |hdmicec, _command| {
+            info!("Mute");
+            return hdmicec.mute();
+        }));
+
+    // Setup a simple button for 4 sources. It's unclear to me if CEC even
+    //supports more than 4 input sources. TODO maybe use a Select entity? that
+    // will require reading the state though, unless we want to use optimistic mode.
+    let sources = (1..5).map(|i| {
+        return device
+            .entity(&format!("Source{}", i), EntityClass::Button(None))
+            .with_icon("mdi:video-input-hdmi")
+            .with_commands(
+                hdmicec.command(&format!("Source{}", i), move |hdmicec, _command| {
+                    info!("Source {}", i);
+                    return hdmicec.set_active_source(i as usize);
+                }),
+            );
+    });
+
+    let mut entities = vec![switch, vol_up, vol_down, mute];
+    entities.extend(sources);
+    return entities;
+}
+
+/// A button or scene for each macro in the config, along with a sensor for how it is going, like "step 2/5: wait for the TV to report ON".
+pub fn macro_entities(
+    device: &Device,
+    hdmicec: &Arc<HdmiCecProcess>,
+    macros: &[MacroConfig],
+) -> Vec<Entity> {
+    return macros
+        .iter()
+        .flat_map(|config| {
+            let runner = MacroRunner::new(hdmicec.clone(), config.clone());
+            let name = config.name.clone().unwrap_or_else(|| config.id.clone());
+            let (entity_class, icon) = match config.platform {
+                MacroPlatform::Button => (EntityClass::Button(None), "mdi:play-box-multiple"),
+                MacroPlatform::Scene => (EntityClass::Scene, "mdi:palette"),
+            };
+
+            // prefixed, so a macro can't take the id of one of the other entities.
+            let id = format!("macro_{}", config.id);
+            let start_runner = runner.clone();
+            let trigger = device
+                .entity(&id, entity_class)
+                .with_display_name(&name)
+                .with_icon(config.icon.as_deref().unwrap_or(icon))
+                .with_commands(SimpleCommand::new(move |_command| {
+                    return start_runner.start();
+                }));
+            let status = device
+                .entity(&format!("{id}_status"), EntityClass::Sensor(None))
+                .with_display_name(&format!("{name} status"))
+                .with_icon("mdi:playlist-play")
+                .with_state(move |state| {
+                    runner.attach_status(state);
+                });
+            return [trigger, status];
+        })
+        .collect();
+}
+
+/// the commands the TV switch accepts: power, and switching sources.
+pub fn tv_schema() -> CommandSchema {
+    return CommandSchema::power().with_sources(source_names());
+}
+
+/// switch the TV on or off, and over to a source, for a command that already passed 'schema'.
+pub fn command_tv(
+    hdmicec: &HdmiCecProcess,
+    schema: &CommandSchema,
+    command: &Command,
+) -> Result<(), BridgeError> {
+    command.power.map_or(Ok(()), |power| {
+        let status = match power {
+            PowerCommand::On => true,
+            PowerCommand::Off => false,
+            PowerCommand::Toggle => hdmicec.tv_state().as_deref() != Some("ON"),
+        };
+        info!("Switching TV {}", if status { "on" } else { "off" });
+        return hdmicec.set_tv(status);
+    })?;
+    // the schema already checked the source is one we know.
+    return command
+        .source
+        .as_ref()
+        .and_then(|source| schema.source_index(source))
+        .map_or(Ok(()), |source| {
+            info!("Source {}", source);
+            return hdmicec.set_active_source(source);
+        });
+}
+
+/// the names of the input sources we can switch to. It's unclear to me if CEC even supports more than 4.
+fn source_names() -> Vec<String> {
+    return (1..5).map(|i| format!("HDMI {i}")).collect();
+}
+
+/// Read-only sensors for watching the bus in monitor mode.
+pub fn monitor_entities(device: &Device, hdmicec: &Arc<HdmiCecProcess>) -> Vec<Entity> {
+    let power_hdmicec = hdmicec.clone();
+    let power = device
+        .entity(
+            "tv",
+            EntityClass::BinarySensor(Some(BinarySensorClass::Power)),
+        )
+        .with_state(move |state| {
+            power_hdmicec.attach_statemanager(state);
+        });
+
+    let source_hdmicec = hdmicec.clone();
+    let source = device
+        .entity("source", EntityClass::Sensor(None))
+        .with_icon("mdi:video-input-hdmi")
+        .with_state(move |state| {
+            source_hdmicec.on_event(move |event| {
+                if let CecEvent::ActiveSource(source) = event {
+                    state.update_state(source.clone());
+                }
+            });
+        });
+
+    let traffic_hdmicec = hdmicec.clone();
+    let traffic = device
+        .entity("traffic", EntityClass::Sensor(None))
+        .with_icon("mdi:swap-horizontal")
+        // every frame on the bus is a lot of history to keep, so this is opt-in.
+        .with_entity_category(EntityCategory::Diagnostic)
+        .enabled_by_default(false)
+        .with_state(move |state| {
+            traffic_hdmicec.on_event(move |event| {
+                if let CecEvent::Traffic(frame) = event {
+                    state.update_state(frame.to_string());
+                }
+            });
+        });
+
+    return vec![power, source, traffic];
+}
+
+/// Diagnostic sensors for the health of the bus, fed from the cec-client output. These are what to check first when a room stops working.
+pub fn diagnostic_entities(device: &Device, hdmicec: &Arc<HdmiCecProcess>) -> Vec<Entity> {
+    let diagnostic =
+        |id: &str, entity_class: EntityClass, value: fn(&CecDiagnostics) -> Option<String>| {
+            let hdmicec = hdmicec.clone();
+            return device
+                .entity(id, entity_class)
+                .with_entity_category(EntityCategory::Diagnostic)
+                .with_state(move |state| {
+                    hdmicec.on_diagnostic(state, value);
+                });
+        };
+    let counter = |id: &str, icon: &str, value: fn(&CecDiagnostics) -> u64| {
+        let hdmicec = hdmicec.clone();
+        return device
+            .entity(id, EntityClass::Sensor(None))
+            .with_icon(icon)
+            .with_state_class(StateClass::TotalIncreasing)
+            .with_entity_category(EntityCategory::Diagnostic)
+            .with_state(move |state| {
+                hdmicec.on_diagnostic(state, move |diagnostics| {
+                    Some(value(diagnostics).to_string())
+                });
+            });
+    };
+
+    return vec![
+        diagnostic(
+            "cec_connection",
+            EntityClass::BinarySensor(Some(BinarySensorClass::Connectivity)),
+            |diagnostics| {
+                diagnostics
+                    .connected
+                    .map(|connected| if connected { "ON" } else { "OFF" }.to_string())
+            },
+        ),
+        diagnostic("libcec_version", EntityClass::Sensor(None), |diagnostics| {
+            diagnostics.libcec_version.clone()
+        })
+        .with_icon("mdi:information-outline"),
+        diagnostic(
+            "firmware_version",
+            EntityClass::Sensor(None),
+            |diagnostics| diagnostics.firmware_version.clone(),
+        )
+        .with_icon("mdi:chip"),
+        diagnostic(
+            "logical_address",
+            EntityClass::Sensor(None),
+            |diagnostics| diagnostics.logical_address.clone(),
+        )
+        .with_icon("mdi:identifier"),
+        diagnostic(
+            "physical_address",
+            EntityClass::Sensor(None),
+            |diagnostics| diagnostics.physical_address.clone(),
+        )
+        .with_icon("mdi:video-input-hdmi"),
+        diagnostic("devices", EntityClass::Sensor(None), |diagnostics| {
+            Some(diagnostics.devices.len().to_string())
+        })
+        .with_icon("mdi:devices")
+        .with_state_class(StateClass::Measurement),
+        counter("frames_sent", "mdi:upload", |diagnostics| {
+            diagnostics.frames_sent
+        }),
+        counter("frames_received", "mdi:download", |diagnostics| {
+            diagnostics.frames_received
+        }),
+        counter(
+            "transmit_failures",
+            "mdi:alert-circle-outline",
+            |diagnostics| diagnostics.transmit_failures,
+        ),
+        diagnostic("last_error", EntityClass::Sensor(None), |diagnostics| {
+            diagnostics.last_error.clone()
+        })
+        .with_icon("mdi:alert"),
+    ];
+}
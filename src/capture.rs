@@ -0,0 +1,139 @@
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::{Error, ErrorKind, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::error;
+
+/// Whether a captured line was sent to the process, or read from its output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureDirection {
+    Input,
+    Output,
+}
+
+impl Display for CaptureDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Input => write!(f, ">"),
+            Self::Output => write!(f, "<"),
+        }
+    }
+}
+
+/// A single line in a capture file. Each line in the file looks like "12.345 < power status: on", with the number of seconds since the capture started, and then ">" for lines we sent, or "<" for lines we read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureLine {
+    pub timestamp: Duration,
+    pub direction: CaptureDirection,
+    pub line: String,
+}
+
+impl CaptureLine {
+    pub fn parse(line: &str) -> Option<Self> {
+        let (timestamp, rest) = line.split_once(' ')?;
+        let (direction, line) = rest.split_once(' ').unwrap_or((rest, ""));
+        let direction = match direction {
+            ">" => CaptureDirection::Input,
+            "<" => CaptureDirection::Output,
+            _ => return None,
+        };
+
+        return Some(Self {
+            timestamp: Duration::try_from_secs_f64(timestamp.parse().ok()?).ok()?,
+            direction,
+            line: line.to_string(),
+        });
+    }
+
+    /// read in a whole capture file.
+    pub fn read_file(path: &str) -> Result<Vec<Self>, Error> {
+        return fs::read_to_string(path)?
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(number, line)| {
+                Self::parse(line).ok_or(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{path}:{}: invalid capture line \"{line}\"", number + 1),
+                ))
+            })
+            .collect();
+    }
+}
+
+impl Display for CaptureLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.3} {} {}",
+            self.timestamp.as_secs_f64(),
+            self.direction,
+            self.line
+        )
+    }
+}
+
+/// Writes every line going in and out of a process to a capture file. This can be cloned to share between the reader thread, and whoever is sending input.
+#[derive(Clone)]
+pub struct CaptureWriter {
+    file: Arc<Mutex<File>>,
+    start: Instant,
+}
+
+impl CaptureWriter {
+    pub fn create(path: &str) -> Result<Self, Error> {
+        return Ok(Self {
+            file: Arc::new(Mutex::new(File::create(path)?)),
+            start: Instant::now(),
+        });
+    }
+
+    pub fn record(&self, direction: CaptureDirection, line: &str) {
+        let capture_line = CaptureLine {
+            timestamp: self.start.elapsed(),
+            direction,
+            line: line.trim_end().to_string(),
+        };
+        let mut file = self.file.lock().expect("could not lock capture file");
+        if let Err(err) = writeln!(file, "{capture_line}") {
+            error!("could not write to capture file: {err}");
+        }
+    }
+}
+
+#[test]
+fn parsing_capture_lines() {
+    let line = CaptureLine::parse("1.250 < power status: on").expect("could not parse line");
+    assert_eq!(line.timestamp, Duration::from_millis(1250));
+    assert_eq!(line.direction, CaptureDirection::Output);
+    assert_eq!(line.line, "power status: on");
+    assert_eq!(line.to_string(), "1.250 < power status: on");
+
+    let line = CaptureLine::parse("0.001 > pow 0.0.0.0").expect("could not parse line");
+    assert_eq!(line.direction, CaptureDirection::Input);
+
+    assert_eq!(CaptureLine::parse("junk"), None);
+    assert_eq!(CaptureLine::parse("1.0 ? what"), None);
+}
+
+#[test]
+fn reading_capture_files() {
+    let path = crate::test_harness::temp_path("reading_capture_files.cap");
+    fs::write(
+        &path,
+        "0.001 > pow 0.0.0.0\n\n1.250 < power status: on\njunk\n",
+    )
+    .expect("could not write capture");
+    let path = path.to_str().expect("temp dir is not utf-8");
+
+    // blank lines still count, so the line number points at the right place.
+    let err = CaptureLine::read_file(path).expect_err("read an invalid capture");
+    assert_eq!(
+        err.to_string(),
+        format!("{path}:4: invalid capture line \"junk\"")
+    );
+}
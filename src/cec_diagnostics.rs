@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::cec_frame::{format_physical_address, logical_address_name, CecFrame, Direction};
+
+/// Another device on the bus, and what it told us about itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CecDevice {
+    pub logical_address: u8,
+    /// the name libcec uses for the logical address, like "Playback 1".
+    pub name: &'static str,
+    pub physical_address: Option<String>,
+    pub osd_name: Option<String>,
+    /// the power state it last reported, as "ON" or "OFF".
+    pub power: Option<String>,
+}
+
+impl CecDevice {
+    fn new(logical_address: u8) -> Self {
+        return Self {
+            logical_address,
+            name: logical_address_name(logical_address),
+            physical_address: None,
+            osd_name: None,
+            power: None,
+        };
+    }
+
+    /// learn what we can from a frame this device sent.
+    fn update(&mut self, frame: &CecFrame) {
+        match (frame.opcode, &frame.parameters[..]) {
+            // <Report Physical Address>
+            (Some(0x84), [high, low, ..]) => {
+                self.physical_address = Some(format_physical_address(&[*high, *low]))
+            }
+            // <Set OSD Name>
+            (Some(0x47), name) => {
+                self.osd_name = Some(String::from_utf8_lossy(name).to_string());
+            }
+            // <Report Power Status>, where 2 and 3 are on their way to on and standby.
+            (Some(0x90), [0 | 2, ..]) => self.power = Some("ON".to_string()),
+            (Some(0x90), [1 | 3, ..]) => self.power = Some("OFF".to_string()),
+            _ => {}
+        }
+    }
+}
+
+/// What we know about the health of the CEC bus, collected from the cec-client output. These are what to check first when the TV stops responding.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CecDiagnostics {
+    /// whether cec-client is connected to the adapter. None until we hear either way.
+    pub connected: Option<bool>,
+    pub libcec_version: Option<String>,
+    pub firmware_version: Option<String>,
+    /// the logical address we registered as, like "Recorder 1 (1)".
+    pub logical_address: Option<String>,
+    /// our physical address, like "1.0.0.0".
+    pub physical_address: Option<String>,
+    /// every other device we received a frame from, by logical address.
+    pub devices: BTreeMap<u8, CecDevice>,
+    pub frames_sent: u64,
+    pub frames_received: u64,
+    pub transmit_failures: u64,
+    pub last_error: Option<String>,
+    /// when the TV last told us its power state, which it does every time we poll it.
+    pub last_power_report: Option<Instant>,
+}
+
+impl CecDiagnostics {
+    /// learn what we can from a line of cec-client output. Returns whether anything changed.
+    pub fn update(&mut self, line: &str) -> bool {
+        if let Some(frame) = CecFrame::parse_traffic(line) {
+            // <Report Power Status> from the TV, with a state we understand. Our own reports to other devices don't count.
+            if frame.opcode == Some(0x90)
+                && frame.direction == Direction::Received
+                && frame.power_state().is_some()
+            {
+                self.last_power_report = Some(Instant::now());
+            }
+            match frame.direction {
+                Direction::Sent => self.frames_sent += 1,
+                Direction::Received => {
+                    self.frames_received += 1;
+                    // 0xf as an initiator is an unregistered device, which could be anything.
+                    if frame.initiator != 0xf {
+                        self.devices
+                            .entry(frame.initiator)
+                            .or_insert_with(|| CecDevice::new(frame.initiator))
+                            .update(&frame);
+                    }
+                }
+            }
+            return true;
+        }
+
+        let message = log_message(line);
+        let lowercase = message.to_lowercase();
+        if let Some(registration) = message.strip_prefix("CEC client registered:") {
+            self.connected = Some(true);
+            self.parse_registration(registration);
+        } else if let Some(status) = message.strip_prefix("power status:") {
+            // cec-client says "unknown" when the TV didn't answer.
+            if status.trim() != "unknown" {
+                self.last_power_report = Some(Instant::now());
+            }
+        } else if lowercase.starts_with("waiting for input") {
+            self.connected = Some(true);
+        } else if lowercase.contains("connection lost")
+            || lowercase.starts_with("unable to open the device")
+            || lowercase.starts_with("could not open a connection")
+        {
+            self.connected = Some(false);
+            self.last_error = Some(message.to_string());
+        } else if transmit_failed(message) {
+            self.transmit_failures += 1;
+            self.last_error = Some(message.to_string());
+        } else if line.starts_with("ERROR:") {
+            self.last_error = Some(message.to_string());
+        } else {
+            return false;
+        }
+        return true;
+    }
+
+    /// cec-client is gone, so nothing is connected anymore.
+    pub fn disconnected(&mut self) -> bool {
+        return self.connected.replace(false) != Some(false);
+    }
+
+    /// pick the versions and addresses out of a line like "libCEC version = 6.0.2, client version = 6.0.2, firmware version = 4, logical address(es) = Recorder 1 (1) , physical address: 1.0.0.0, compiled on ...".
+    fn parse_registration(&mut self, registration: &str) {
+        for field in registration.split(',') {
+            let (key, value) = match field.split_once(" = ").or(field.split_once(": ")) {
+                Some((key, value)) => (key.trim(), Some(value.trim().to_string())),
+                None => continue,
+            };
+            match key {
+                "libCEC version" => self.libcec_version = value,
+                "firmware version" => self.firmware_version = value,
+                "logical address(es)" => self.logical_address = value,
+                "physical address" => self.physical_address = value,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// whether a line of cec-client output says a frame we sent was not acknowledged.
+pub fn transmit_failed(line: &str) -> bool {
+    let lowercase = line.to_lowercase();
+    return lowercase.contains("not acked")
+        || lowercase.contains("transmit failed")
+        || lowercase.contains("failed to transmit");
+}
+
+/// strip the log level and timestamp from a line like "NOTICE:  [   281]\tconnection opened". Lines without them are returned as they are.
+fn log_message(line: &str) -> &str {
+    return match line.split_once(']') {
+        Some((prefix, message)) if prefix.ends_with(|c: char| c.is_ascii_digit()) => message.trim(),
+        _ => line.trim(),
+    };
+}
+
+#[test]
+fn collecting_diagnostics() {
+    let mut diagnostics = CecDiagnostics::default();
+    assert!(!diagnostics.update("opening a connection to the CEC adapter..."));
+    assert!(diagnostics.update("NOTICE:  [             281]\tCEC client registered: libCEC version = 6.0.2, client version = 6.0.2, firmware version = 4, logical address(es) = Recorder 1 (1) , physical address: 1.0.0.0, compiled on Linux-6.1.0 ... , features: P8_USB, DRM, P8_detect, randr, RPi"));
+    assert_eq!(diagnostics.connected, Some(true));
+    assert_eq!(diagnostics.libcec_version.as_deref(), Some("6.0.2"));
+    assert_eq!(diagnostics.firmware_version.as_deref(), Some("4"));
+    assert_eq!(
+        diagnostics.logical_address.as_deref(),
+        Some("Recorder 1 (1)")
+    );
+    assert_eq!(diagnostics.physical_address.as_deref(), Some("1.0.0.0"));
+
+    diagnostics.update("TRAFFIC: [          4310]\t<< 10:8f");
+    diagnostics.update("TRAFFIC: [          4350]\t<< 10:90:00");
+    diagnostics.update("power status: unknown");
+    assert!(diagnostics.last_power_report.is_none());
+    diagnostics.update("TRAFFIC: [          4412]\t>> 01:90:00");
+    diagnostics.update("TRAFFIC: [          4500]\t>> 5f:72:01");
+    diagnostics.update("TRAFFIC: [          4600]\t>> 0f:36");
+    assert_eq!(diagnostics.frames_sent, 2);
+    assert_eq!(diagnostics.frames_received, 3);
+    assert_eq!(diagnostics.devices.keys().collect::<Vec<_>>(), vec![&0, &5]);
+    assert_eq!(diagnostics.devices[&0].power.as_deref(), Some("ON"));
+    diagnostics.update("TRAFFIC: [          4700]\t>> 5f:84:10:00:05");
+    diagnostics.update("TRAFFIC: [          4800]\t>> 51:47:53:6f:75:6e:64:62:61:72");
+    assert_eq!(
+        diagnostics.devices[&5].physical_address.as_deref(),
+        Some("1.0.0.0")
+    );
+    assert_eq!(
+        diagnostics.devices[&5].osd_name.as_deref(),
+        Some("Soundbar")
+    );
+    assert!(diagnostics.last_power_report.is_some());
+
+    diagnostics.update("DEBUG:   [          5000]\tcommand 'give device power status' was not acked by the controller");
+    assert_eq!(diagnostics.transmit_failures, 1);
+    diagnostics.update("ERROR:   [          6000]\tcould not read from the adapter");
+    assert_eq!(
+        diagnostics.last_error.as_deref(),
+        Some("could not read from the adapter")
+    );
+
+    assert!(diagnostics.disconnected());
+    assert!(!diagnostics.disconnected());
+    assert_eq!(diagnostics.connected, Some(false));
+}
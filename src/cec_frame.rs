@@ -0,0 +1,273 @@
+use std::fmt::Display;
+
+/// Whether a frame was received from the bus, or sent by us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+/// A single frame on the CEC bus, like "0f:36". See https://www.cec-o-matic.com/ for a good reference on what these mean.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CecFrame {
+    pub direction: Direction,
+    pub initiator: u8,
+    pub destination: u8,
+    pub opcode: Option<u8>,
+    pub parameters: Vec<u8>,
+}
+
+impl CecFrame {
+    /// parse a frame from cec-client's traffic log, which looks like "TRAFFIC: [     4125]  >> 0f:36".
+    pub fn parse_traffic(line: &str) -> Option<Self> {
+        let traffic = line.strip_prefix("TRAFFIC:")?;
+        let (direction, frame) = if let Some((_, frame)) = traffic.split_once(">>") {
+            (Direction::Received, frame)
+        } else if let Some((_, frame)) = traffic.split_once("<<") {
+            (Direction::Sent, frame)
+        } else {
+            return None;
+        };
+        return Self::parse(frame.trim(), direction);
+    }
+
+    /// parse a frame from colon separated hex bytes, like "0f:36".
+    pub fn parse(frame: &str, direction: Direction) -> Option<Self> {
+        let bytes = frame
+            .split(':')
+            .map(|byte| u8::from_str_radix(byte.trim(), 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        let (header, rest) = bytes.split_first()?;
+
+        return Some(Self {
+            direction,
+            initiator: header >> 4,
+            destination: header & 0x0f,
+            opcode: rest.first().copied(),
+            parameters: rest.iter().skip(1).copied().collect(),
+        });
+    }
+
+    /// the TV's power state, if this frame tells us anything about it. This is in the same "ON"/"OFF" format as the state topics.
+    pub fn power_state(&self) -> Option<&'static str> {
+        match (self.opcode?, self.parameters.first()) {
+            // <Report Power Status> from the TV. 0 is on, 2 is "on, but still warming up".
+            (0x90, Some(0 | 2)) if self.initiator == 0 => Some("ON"),
+            (0x90, Some(1 | 3)) if self.initiator == 0 => Some("OFF"),
+            // <Image View On> and <Text View On> turn the TV on.
+            (0x04 | 0x0d, _) if self.destination == 0 => Some("ON"),
+            // <Standby> either from the TV, or sent to the TV.
+            (0x36, _)
+                if self.initiator == 0 || self.destination == 0 || self.destination == 0x0f =>
+            {
+                Some("OFF")
+            }
+            _ => None,
+        }
+    }
+
+    /// the physical address of the new active source, if this frame changes it. formatted like "1.0.0.0".
+    pub fn active_source(&self) -> Option<String> {
+        let address = match self.opcode? {
+            // <Active Source> and <Set Stream Path> have the address as the only parameter.
+            0x82 | 0x86 => self.parameters.get(0..2)?,
+            // <Routing Change> has the old and then the new address.
+            0x80 => self.parameters.get(2..4)?,
+            _ => return None,
+        };
+        return Some(format_physical_address(address));
+    }
+
+    /// the raw frame as colon separated hex bytes, in the same format as cec-client's "tx" command.
+    pub fn to_hex(&self) -> String {
+        let mut bytes = vec![format!("{:x}{:x}", self.initiator, self.destination)];
+        bytes.extend(self.opcode.iter().map(|opcode| format!("{opcode:02x}")));
+        bytes.extend(self.parameters.iter().map(|byte| format!("{byte:02x}")));
+        return bytes.join(":");
+    }
+
+    pub fn opcode_name(&self) -> &'static str {
+        match self.opcode {
+            None => "Poll",
+            Some(0x00) => "Feature Abort",
+            Some(0x04) => "Image View On",
+            Some(0x0d) => "Text View On",
+            Some(0x32) => "Set Menu Language",
+            Some(0x36) => "Standby",
+            Some(0x44) => "User Control Pressed",
+            Some(0x45) => "User Control Released",
+            Some(0x46) => "Give OSD Name",
+            Some(0x47) => "Set OSD Name",
+            Some(0x70) => "System Audio Mode Request",
+            Some(0x71) => "Give Audio Status",
+            Some(0x72) => "Set System Audio Mode",
+            Some(0x7a) => "Report Audio Status",
+            Some(0x7d) => "Give System Audio Mode Status",
+            Some(0x7e) => "System Audio Mode Status",
+            Some(0x80) => "Routing Change",
+            Some(0x81) => "Routing Information",
+            Some(0x82) => "Active Source",
+            Some(0x83) => "Give Physical Address",
+            Some(0x84) => "Report Physical Address",
+            Some(0x85) => "Request Active Source",
+            Some(0x86) => "Set Stream Path",
+            Some(0x87) => "Device Vendor ID",
+            Some(0x8c) => "Give Device Vendor ID",
+            Some(0x8f) => "Give Device Power Status",
+            Some(0x90) => "Report Power Status",
+            Some(0x9d) => "Inactive Source",
+            Some(0x9e) => "CEC Version",
+            Some(0x9f) => "Get CEC Version",
+            Some(_) => "Unknown",
+        }
+    }
+}
+
+/// format the two bytes of a physical address like "1.0.0.0".
+pub fn format_physical_address(address: &[u8]) -> String {
+    return format!(
+        "{:x}.{:x}.{:x}.{:x}",
+        address[0] >> 4,
+        address[0] & 0x0f,
+        address[1] >> 4,
+        address[1] & 0x0f
+    );
+}
+
+/// The keys of a remote control, as sent in <User Control Pressed>. These are the most useful ones, out of the full list in the CEC spec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum_macros::Display, strum_macros::EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum UserControl {
+    Select,
+    Up,
+    Down,
+    Left,
+    Right,
+    RootMenu,
+    Exit,
+    Number0,
+    Number1,
+    Number2,
+    Number3,
+    Number4,
+    Number5,
+    Number6,
+    Number7,
+    Number8,
+    Number9,
+    ChannelUp,
+    ChannelDown,
+    InputSelect,
+    Power,
+    VolumeUp,
+    VolumeDown,
+    Mute,
+    Play,
+    Stop,
+    Pause,
+    Rewind,
+    FastForward,
+    PowerToggle,
+    PowerOff,
+    PowerOn,
+}
+
+impl UserControl {
+    /// the UI command code for the key.
+    pub fn code(&self) -> u8 {
+        return match self {
+            Self::Select => 0x00,
+            Self::Up => 0x01,
+            Self::Down => 0x02,
+            Self::Left => 0x03,
+            Self::Right => 0x04,
+            Self::RootMenu => 0x09,
+            Self::Exit => 0x0d,
+            Self::Number0 => 0x20,
+            Self::Number1 => 0x21,
+            Self::Number2 => 0x22,
+            Self::Number3 => 0x23,
+            Self::Number4 => 0x24,
+            Self::Number5 => 0x25,
+            Self::Number6 => 0x26,
+            Self::Number7 => 0x27,
+            Self::Number8 => 0x28,
+            Self::Number9 => 0x29,
+            Self::ChannelUp => 0x30,
+            Self::ChannelDown => 0x31,
+            Self::InputSelect => 0x34,
+            Self::Power => 0x40,
+            Self::VolumeUp => 0x41,
+            Self::VolumeDown => 0x42,
+            Self::Mute => 0x43,
+            Self::Play => 0x44,
+            Self::Stop => 0x45,
+            Self::Pause => 0x46,
+            Self::Rewind => 0x48,
+            Self::FastForward => 0x49,
+            Self::PowerToggle => 0x6b,
+            Self::PowerOff => 0x6c,
+            Self::PowerOn => 0x6d,
+        };
+    }
+}
+
+/// the name libcec uses for each logical address.
+pub fn logical_address_name(address: u8) -> &'static str {
+    match address {
+        0x0 => "TV",
+        0x1 => "Recorder 1",
+        0x2 => "Recorder 2",
+        0x3 => "Tuner 1",
+        0x4 => "Playback 1",
+        0x5 => "Audio",
+        0x6 => "Tuner 2",
+        0x7 => "Tuner 3",
+        0x8 => "Playback 2",
+        0x9 => "Recorder 3",
+        0xa => "Tuner 4",
+        0xb => "Playback 3",
+        0xc => "Reserved 1",
+        0xd => "Reserved 2",
+        0xe => "Free use",
+        _ => "Broadcast",
+    }
+}
+
+impl Display for CecFrame {
+    /// a human readable version of the frame, like "TV -> Broadcast: Standby (0f:36)".
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(
+            f,
+            "{} -> {}: {} ({})",
+            logical_address_name(self.initiator),
+            logical_address_name(self.destination),
+            self.opcode_name(),
+            self.to_hex()
+        );
+    }
+}
+
+#[test]
+fn parsing_traffic_lines() {
+    let frame = CecFrame::parse_traffic("TRAFFIC: [          4125]\t>> 0f:36").expect("no frame");
+    assert_eq!(frame.direction, Direction::Received);
+    assert_eq!(frame.initiator, 0);
+    assert_eq!(frame.destination, 0xf);
+    assert_eq!(frame.opcode, Some(0x36));
+    assert_eq!(frame.power_state(), Some("OFF"));
+    assert_eq!(frame.to_string(), "TV -> Broadcast: Standby (0f:36)");
+
+    let frame =
+        CecFrame::parse_traffic("TRAFFIC: [          4310]\t<< 1f:82:20:00").expect("no frame");
+    assert_eq!(frame.direction, Direction::Sent);
+    assert_eq!(frame.active_source(), Some("2.0.0.0".to_string()));
+
+    let frame = CecFrame::parse_traffic("TRAFFIC: [  4412]\t>> 01:90:00").expect("no frame");
+    assert_eq!(frame.power_state(), Some("ON"));
+
+    assert_eq!(CecFrame::parse_traffic("TRAFFIC: [  4412]\t>> zz"), None);
+    assert_eq!("volume_up".parse(), Ok(UserControl::VolumeUp));
+    assert_eq!(UserControl::VolumeUp.code(), 0x41);
+    assert_eq!(CecFrame::parse_traffic("power status: on"), None);
+}
@@ -0,0 +1,423 @@
+use std::time::Instant;
+
+use log::debug;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::{
+    cec_frame::{format_physical_address, logical_address_name, CecFrame, Direction},
+    config::{CecDeviceType, SimulatedDeviceConfig, SimulatorConfig},
+    process::CommandProcess,
+};
+
+const BROADCAST: u8 = 0xf;
+
+struct SimulatedDevice {
+    logical_address: u8,
+    physical_address: [u8; 2],
+    osd_name: String,
+    power: bool,
+    volume: Option<u8>,
+    muted: bool,
+}
+
+impl SimulatedDevice {
+    fn from_config(config: &SimulatedDeviceConfig) -> Self {
+        Self {
+            logical_address: config.logical_address,
+            physical_address: parse_physical_address(&config.physical_address)
+                .unwrap_or([0xff, 0xff]),
+            osd_name: config.osd_name.clone(),
+            power: config.power,
+            // validate() already checked this, but a config doesn't have to go through it.
+            volume: config.volume.map(|volume| volume.min(100)),
+            muted: false,
+        }
+    }
+
+    fn power_status(&self) -> &'static str {
+        if self.power {
+            "on"
+        } else {
+            "standby"
+        }
+    }
+
+    /// the <Report Audio Status> parameter: the mute flag in the top bit, and the volume in the rest.
+    fn audio_status(&self) -> u8 {
+        return (self.muted as u8) << 7 | self.volume.unwrap_or(0);
+    }
+}
+
+/// turn "1.2.0.0" into [0x12, 0x00].
+pub fn parse_physical_address(address: &str) -> Option<[u8; 2]> {
+    let nibbles = address
+        .split('.')
+        .map(|nibble| u8::from_str_radix(nibble, 16).ok().filter(|n| *n < 16))
+        .collect::<Option<Vec<u8>>>()?;
+    match nibbles[..] {
+        [a, b, c, d] => Some([a << 4 | b, c << 4 | d]),
+        _ => None,
+    }
+}
+
+/// A fake cec-client, that understands the same commands on stdin, and writes the same kind of output, for a simulated bus full of devices. This is what the tests run against, instead of a real TV.
+pub struct CecSimulator {
+    devices: Vec<SimulatedDevice>,
+    /// the logical address the simulated cec-client registers as.
+    our_address: u8,
+    active_source: Option<[u8; 2]>,
+    start: Instant,
+}
+
+impl CecSimulator {
+    /// 'device_type' picks our logical address, just like it does for cec-client. Without it, we are "Recorder 1", the libcec default.
+    pub fn new(config: &SimulatorConfig, device_type: Option<&CecDeviceType>) -> Self {
+        Self {
+            devices: config
+                .devices
+                .iter()
+                .map(SimulatedDevice::from_config)
+                .collect(),
+            our_address: device_type
+                .unwrap_or(&CecDeviceType::Recording)
+                .logical_address(),
+            active_source: None,
+            start: Instant::now(),
+        }
+    }
+
+    /// run the simulator on a separate task, connected up like a real cec-client process would be.
+    pub fn spawn(mut self) -> CommandProcess {
+        let (input, simulator_input) = tokio::io::duplex(4096);
+        let (output, mut simulator_output) = tokio::io::duplex(4096);
+
+        tokio::spawn(async move {
+            let mut output = self.connect();
+            let mut commands = BufReader::new(simulator_input).lines();
+            loop {
+                let written = simulator_output
+                    .write_all(
+                        output
+                            .iter()
+                            .map(|line| format!("{line}\n"))
+                            .collect::<String>()
+                            .as_bytes(),
+                    )
+                    .await;
+                if written.is_err() {
+                    break;
+                }
+                let line = match commands.next_line().await {
+                    Ok(Some(line)) => line,
+                    _ => break,
+                };
+                debug!("simulator got command: {line}");
+                output = match self.handle(&line) {
+                    Some(output) => output,
+                    None => break,
+                };
+            }
+            debug!("simulator exiting");
+        });
+
+        return CommandProcess::from_io(Box::new(input), Box::new(output));
+    }
+
+    /// what cec-client prints once it has connected to the adapter, and registered on the bus.
+    fn connect(&self) -> Vec<String> {
+        let version = env!("CARGO_PKG_VERSION");
+        return vec![
+            "opening a connection to the CEC adapter...".to_string(),
+            format!(
+                "NOTICE:  [{:>16}]\tCEC client registered: libCEC version = {version}, client version = {version}, firmware version = 0, logical address(es) = {} ({}) , physical address: 1.0.0.0, compiled on hdmicec2mqtt simulator",
+                self.start.elapsed().as_millis(),
+                logical_address_name(self.our_address),
+                self.our_address
+            ),
+            "waiting for input".to_string(),
+        ];
+    }
+
+    /// handle a single line of cec-client input, and return the lines it would output. Returns None for the quit command.
+    pub fn handle(&mut self, input: &str) -> Option<Vec<String>> {
+        let mut words = input.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let argument = words.next();
+        let destination = argument.and_then(|address| self.find_device(address));
+
+        let output = match (command, destination) {
+            ("q", _) => return None,
+            ("on", Some(destination)) => {
+                self.transmit(self.our_address << 4 | destination, &[0x04])
+            }
+            ("standby", Some(destination)) => {
+                self.transmit(self.our_address << 4 | destination, &[0x36])
+            }
+            ("pow", Some(destination)) => {
+                let mut output = self.transmit(self.our_address << 4 | destination, &[0x8f]);
+                self.device(destination).map(|device| {
+                    output.push(format!("power status: {}", device.power_status()));
+                });
+                output
+            }
+            ("volup", _) => self.press_audio_key(0x41),
+            ("voldown", _) => self.press_audio_key(0x42),
+            ("mute", _) => self.press_audio_key(0x43),
+            ("tx", _) => match argument.and_then(|frame| CecFrame::parse(frame, Direction::Sent)) {
+                Some(frame) => {
+                    let mut bytes: Vec<u8> = frame.opcode.into_iter().collect();
+                    bytes.extend(frame.parameters);
+                    self.transmit(frame.initiator << 4 | frame.destination, &bytes)
+                }
+                None => vec![format!("invalid frame: {}", argument.unwrap_or(""))],
+            },
+            ("scan", _) => self.scan(),
+            _ => vec![],
+        };
+        return Some(output);
+    }
+
+    /// find a device by its logical address ("0"), or physical address ("1.0.0.0").
+    fn find_device(&self, address: &str) -> Option<u8> {
+        if address.contains('.') {
+            let physical_address = parse_physical_address(address)?;
+            return self
+                .devices
+                .iter()
+                .find(|device| device.physical_address == physical_address)
+                .map(|device| device.logical_address);
+        }
+        return u8::from_str_radix(address, 16).ok().filter(|a| *a < 16);
+    }
+
+    fn device(&mut self, logical_address: u8) -> Option<&mut SimulatedDevice> {
+        return self
+            .devices
+            .iter_mut()
+            .find(|device| device.logical_address == logical_address);
+    }
+
+    fn audio_system(&self) -> Option<u8> {
+        return self
+            .devices
+            .iter()
+            .find(|device| device.volume.is_some())
+            .map(|device| device.logical_address);
+    }
+
+    fn press_audio_key(&mut self, key: u8) -> Vec<String> {
+        match self.audio_system() {
+            Some(audio) => {
+                let mut output = self.transmit(self.our_address << 4 | audio, &[0x44, key]);
+                output.extend(self.transmit(self.our_address << 4 | audio, &[0x45]));
+                output
+            }
+            None => vec![],
+        }
+    }
+
+    fn traffic_line(&self, direction: Direction, header: u8, bytes: &[u8]) -> String {
+        let arrow = match direction {
+            Direction::Sent => "<<",
+            Direction::Received => ">>",
+        };
+        let mut frame = vec![format!("{header:02x}")];
+        frame.extend(bytes.iter().map(|byte| format!("{byte:02x}")));
+        return format!(
+            "TRAFFIC: [{:>16}]\t{arrow} {}",
+            self.start.elapsed().as_millis(),
+            frame.join(":")
+        );
+    }
+
+    /// send a frame on to the bus, and return the traffic it causes, including any replies.
+    fn transmit(&mut self, header: u8, bytes: &[u8]) -> Vec<String> {
+        let mut output = vec![self.traffic_line(Direction::Sent, header, bytes)];
+        self.receive(header >> 4, header & 0x0f, bytes)
+            .into_iter()
+            .for_each(|(reply_header, reply)| {
+                output.push(self.traffic_line(Direction::Received, reply_header, &reply));
+            });
+        return output;
+    }
+
+    /// apply a frame to the simulated devices, and return the replies they send.
+    fn receive(&mut self, initiator: u8, destination: u8, bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let reply_header = destination << 4 | initiator;
+        let active_source = self.active_source;
+        let device = match self.device(destination) {
+            Some(device) => Some(device),
+            None if destination == BROADCAST => None,
+            // nobody is there to acknowledge the frame.
+            None => return vec![],
+        };
+
+        match (bytes, device) {
+            ([0x04 | 0x0d], Some(device)) => {
+                device.power = true;
+            }
+            ([0x36], Some(device)) => {
+                device.power = false;
+            }
+            ([0x36], None) => {
+                self.devices
+                    .iter_mut()
+                    .for_each(|device| device.power = false);
+            }
+            ([0x8f], Some(device)) => {
+                let status = if device.power { 0x00 } else { 0x01 };
+                return vec![(reply_header, vec![0x90, status])];
+            }
+            ([0x46], Some(device)) => {
+                let mut reply = vec![0x47];
+                reply.extend(device.osd_name.bytes());
+                return vec![(reply_header, reply)];
+            }
+            ([0x83], Some(device)) => {
+                let address = device.physical_address;
+                return vec![(
+                    device.logical_address << 4 | BROADCAST,
+                    vec![0x84, address[0], address[1], 0x00],
+                )];
+            }
+            ([0x85], None) => {
+                // whoever is the active source answers.
+                return self
+                    .devices
+                    .iter()
+                    .find(|device| Some(device.physical_address) == active_source)
+                    .map(|device| {
+                        let address = device.physical_address;
+                        (
+                            device.logical_address << 4 | BROADCAST,
+                            vec![0x82, address[0], address[1]],
+                        )
+                    })
+                    .into_iter()
+                    .collect();
+            }
+            ([0x82, high, low], None) => {
+                self.active_source = Some([*high, *low]);
+                // the TV wakes up to switch over to the new source.
+                self.device(0).map(|tv| tv.power = true);
+            }
+            ([0x44, key], Some(device)) if device.volume.is_some() => {
+                let volume = device.volume.unwrap_or(0);
+                match key {
+                    0x41 => device.volume = Some(volume.saturating_add(1).min(100)),
+                    0x42 => device.volume = Some(volume.saturating_sub(1)),
+                    0x43 => device.muted = !device.muted,
+                    _ => {}
+                }
+                return vec![(reply_header, vec![0x7a, device.audio_status()])];
+            }
+            ([0x71], Some(device)) if device.volume.is_some() => {
+                return vec![(reply_header, vec![0x7a, device.audio_status()])];
+            }
+            // <Set Audio Volume Level>, from CEC 2.0.
+            ([0x73, level], Some(device)) if device.volume.is_some() => {
+                device.volume = Some((*level).min(100));
+                return vec![(reply_header, vec![0x7a, device.audio_status()])];
+            }
+            // <System Audio Mode Request> turns it on with the source's address, and off without.
+            ([0x70, address @ ..], Some(device)) if device.volume.is_some() => {
+                return vec![(
+                    device.logical_address << 4 | BROADCAST,
+                    vec![0x72, !address.is_empty() as u8],
+                )];
+            }
+            _ => {}
+        }
+        return vec![];
+    }
+
+    /// the same report cec-client prints for its "scan" command.
+    fn scan(&self) -> Vec<String> {
+        let mut output = vec![
+            "requesting CEC bus information ...".to_string(),
+            "CEC bus information".to_string(),
+            "===================".to_string(),
+        ];
+        self.devices.iter().for_each(|device| {
+            output.push(format!(
+                "device #{:x}: {}",
+                device.logical_address,
+                crate::cec_frame::logical_address_name(device.logical_address)
+            ));
+            output.push(format!(
+                "address:       {}",
+                format_physical_address(&device.physical_address)
+            ));
+            let active = Some(device.physical_address) == self.active_source;
+            output.push(format!(
+                "active source: {}",
+                if active { "yes" } else { "no" }
+            ));
+            output.push(format!("osd string:    {}", device.osd_name));
+            output.push(format!("power status:  {}", device.power_status()));
+            output.push("".to_string());
+        });
+        output.push(format!(
+            "currently active source: {}",
+            self.active_source
+                .map(|address| format_physical_address(&address))
+                .unwrap_or("unknown".to_string())
+        ));
+        return output;
+    }
+}
+
+#[test]
+fn simulated_power() {
+    let mut simulator = CecSimulator::new(&SimulatorConfig::default(), None);
+
+    let output = simulator.handle("pow 0.0.0.0").expect("simulator quit");
+    assert_eq!(output.last().unwrap(), "power status: standby");
+
+    let output = simulator.handle("on 0.0.0.0").expect("simulator quit");
+    assert!(output[0].ends_with("<< 10:04"));
+
+    let output = simulator.handle("pow 0").expect("simulator quit");
+    assert!(output[1].ends_with(">> 01:90:00"));
+    assert_eq!(output[2], "power status: on");
+
+    simulator.handle("tx 1f:36").expect("simulator quit");
+    let output = simulator.handle("pow 0").expect("simulator quit");
+    assert_eq!(output.last().unwrap(), "power status: standby");
+
+    assert_eq!(simulator.handle("q"), None);
+}
+
+#[test]
+fn simulated_audio_and_sources() {
+    let mut simulator = CecSimulator::new(&SimulatorConfig::default(), None);
+
+    let output = simulator.handle("volup").expect("simulator quit");
+    // 21, up from the default of 20.
+    assert!(output[1].ends_with(">> 51:7a:15"));
+
+    let output = simulator.handle("mute").expect("simulator quit");
+    assert!(output[1].ends_with(">> 51:7a:95"));
+
+    simulator.handle("tx 1f:82:20:00").expect("simulator quit");
+    let output = simulator.handle("scan").expect("simulator quit");
+    assert_eq!(output.last().unwrap(), "currently active source: 2.0.0.0");
+    assert!(output.contains(&"osd string:    Soundbar".to_string()));
+    // switching sources wakes up the TV.
+    assert!(output.contains(&"power status:  on".to_string()));
+}
+
+#[test]
+fn simulated_volume_limits() {
+    let mut config = SimulatorConfig::default();
+    config.devices[1].volume = Some(u8::MAX);
+    let mut simulator = CecSimulator::new(&config, None);
+
+    // the configured volume is out of range to begin with, so it starts out at 100.
+    let output = simulator.handle("tx 15:71").expect("simulator quit");
+    assert!(output.last().unwrap().ends_with(">> 51:7a:64"));
+
+    let output = simulator.handle("volup").expect("simulator quit");
+    // 100, since the volume never goes any higher.
+    assert!(output[1].ends_with(">> 51:7a:64"));
+}
@@ -0,0 +1,279 @@
+//! One-shot commands, like `hdmicec2mqtt power on`, that work on the CEC bus directly without MQTT. They are for scripting, and for troubleshooting on the box the adapter is plugged into.
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Error};
+use clap::{Subcommand, ValueEnum};
+use log::warn;
+use tokio::sync::broadcast;
+
+use crate::bridge::tv_schema;
+use crate::cec_diagnostics::CecDevice;
+use crate::cec_frame::{CecFrame, Direction};
+use crate::config::CecConfig;
+use crate::hdmicec_entity::{CecEvent, HdmiCecProcess};
+
+/// how long cec-client gets to open the adapter.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+/// how long to wait for the TV to answer a power poll.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// how long the bus has to be quiet, before we stop waiting for replies.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(2);
+/// how long a scan can take at most, even on a busy bus.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(30);
+const QUIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The one-shot commands, as subcommands of the main command line.
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum CliCommand {
+    /// Turn the TV on or off, or print its power state.
+    Power {
+        #[arg(value_enum)]
+        state: PowerArgument,
+    },
+    /// Switch to a source, by its number or name, like "2" or "HDMI 2".
+    Source { source: String },
+    /// Turn the volume up or down.
+    Volume {
+        #[arg(value_enum)]
+        direction: VolumeDirection,
+    },
+    /// List every device on the bus.
+    Scan,
+    /// Send a raw frame, like "10:8f", and print the replies.
+    Send { frame: String },
+    /// Print the traffic on the bus, until stopped.
+    Monitor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PowerArgument {
+    On,
+    Off,
+    Status,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VolumeDirection {
+    Up,
+    Down,
+}
+
+/// Start up cec-client, run 'command', and quit again. Only monitor runs until 'shutdown' completes.
+pub async fn run(
+    config: &CecConfig,
+    command: CliCommand,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Error> {
+    let mut config = config.clone();
+    // watching doesn't need a logical address, so don't take one from anything else on the bus.
+    config.monitor |= command == CliCommand::Monitor;
+    let hdmicec = HdmiCecProcess::new(&config)?;
+    let mut events = hdmicec.subscribe();
+    hdmicec.listen()?;
+    connect(&hdmicec).await?;
+
+    let result = match command {
+        CliCommand::Power {
+            state: PowerArgument::On,
+        } => hdmicec.set_tv(true).map_err(Error::from),
+        CliCommand::Power {
+            state: PowerArgument::Off,
+        } => hdmicec.set_tv(false).map_err(Error::from),
+        CliCommand::Power {
+            state: PowerArgument::Status,
+        } => power_status(&hdmicec, &mut events).await.map(|state| {
+            println!("{state}");
+        }),
+        CliCommand::Source { source } => switch_source(&hdmicec, &source),
+        CliCommand::Volume {
+            direction: VolumeDirection::Up,
+        } => hdmicec.volume_up().map_err(Error::from),
+        CliCommand::Volume {
+            direction: VolumeDirection::Down,
+        } => hdmicec.volume_down().map_err(Error::from),
+        CliCommand::Scan => scan(&hdmicec, &mut events).await.map(|devices| {
+            devices
+                .iter()
+                .for_each(|device| println!("{}", describe(device)));
+        }),
+        CliCommand::Send { frame } => send(&hdmicec, &mut events, &frame).await,
+        CliCommand::Monitor => {
+            tokio::select! {
+                _ = monitor(&mut events) => {},
+                _ = shutdown => {},
+            }
+            Ok(())
+        }
+    };
+
+    if let Err(err) = hdmicec.quit(QUIT_TIMEOUT).await {
+        warn!("could not stop cec-client: {err}");
+    }
+    return result;
+}
+
+/// wait for cec-client to open the adapter. Anything we send before then would be lost.
+async fn connect(hdmicec: &HdmiCecProcess) -> Result<(), Error> {
+    let mut diagnostics = hdmicec.diagnostics();
+    let connected = tokio::time::timeout(
+        CONNECT_TIMEOUT,
+        diagnostics.wait_for(|diagnostics| diagnostics.connected.is_some()),
+    )
+    .await
+    .context("timed out waiting for cec-client to connect")?
+    .map(|diagnostics| diagnostics.clone())
+    .context("cec-client stopped")?;
+    if connected.connected != Some(true) {
+        return Err(anyhow!(
+            "cec-client could not connect: {}",
+            connected.last_error.unwrap_or_default()
+        ));
+    }
+    return Ok(());
+}
+
+/// poll the TV, and wait for the answer.
+async fn power_status(
+    hdmicec: &HdmiCecProcess,
+    events: &mut broadcast::Receiver<CecEvent>,
+) -> Result<String, Error> {
+    hdmicec.query_tv_state()?;
+    return tokio::time::timeout(REPLY_TIMEOUT, async {
+        loop {
+            match events.recv().await {
+                Ok(CecEvent::Power(state)) => return Ok(state),
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => {
+                    return Err(anyhow!("cec-client stopped"))
+                }
+            }
+        }
+    })
+    .await
+    .context("the TV did not report its power state")?;
+}
+
+/// a source is either its number, or one of the names the TV switch takes, like "HDMI 2".
+fn switch_source(hdmicec: &HdmiCecProcess, source: &str) -> Result<(), Error> {
+    let schema = tv_schema();
+    let index = source
+        .parse::<usize>()
+        .ok()
+        .filter(|index| (1..=schema.sources.len()).contains(index))
+        .or_else(|| schema.source_index(source))
+        .ok_or_else(|| {
+            anyhow!(
+                "unknown source \"{source}\", it should be 1-{} or one of {:?}",
+                schema.sources.len(),
+                schema.sources
+            )
+        })?;
+    return Ok(hdmicec.set_active_source(index)?);
+}
+
+/// poll every logical address, and collect whoever answers.
+async fn scan(
+    hdmicec: &HdmiCecProcess,
+    events: &mut broadcast::Receiver<CecEvent>,
+) -> Result<Vec<CecDevice>, Error> {
+    hdmicec.poll_devices()?;
+    // an address without a device takes a while to time out on a real bus, so there is no telling how long this takes.
+    let _ = tokio::time::timeout(SCAN_TIMEOUT, wait_for_idle(events, |_| {})).await;
+    let devices = hdmicec
+        .diagnostics()
+        .borrow()
+        .devices
+        .values()
+        .cloned()
+        .collect();
+    return Ok(devices);
+}
+
+/// one line for each device, like "5  Audio         1.0.0.0  Soundbar  OFF".
+fn describe(device: &CecDevice) -> String {
+    return format!(
+        "{:<2} {:<13} {:<8} {:<14} {}",
+        device.logical_address,
+        device.name,
+        device.physical_address.as_deref().unwrap_or("?"),
+        device.osd_name.as_deref().unwrap_or(""),
+        device.power.as_deref().unwrap_or(""),
+    )
+    .trim_end()
+    .to_string();
+}
+
+/// send a raw frame from us, and print whatever comes back.
+async fn send(
+    hdmicec: &HdmiCecProcess,
+    events: &mut broadcast::Receiver<CecEvent>,
+    frame: &str,
+) -> Result<(), Error> {
+    let frame = CecFrame::parse(frame, Direction::Sent)
+        .ok_or_else(|| anyhow!("invalid frame \"{frame}\", it should look like \"10:8f\""))?;
+    hdmicec.transmit(&frame)?;
+    wait_for_idle(events, print_event).await;
+    return Ok(());
+}
+
+async fn monitor(events: &mut broadcast::Receiver<CecEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => print_event(&event),
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("missed {missed} events");
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+fn print_event(event: &CecEvent) {
+    match event {
+        CecEvent::Traffic(frame) => println!("{frame}"),
+        CecEvent::Power(state) => println!("TV power: {state}"),
+        CecEvent::ActiveSource(source) => println!("active source: {source}"),
+        CecEvent::Command { .. } => {}
+    }
+}
+
+/// pass events to 'func', until the bus has been quiet for a while.
+async fn wait_for_idle<F: Fn(&CecEvent)>(events: &mut broadcast::Receiver<CecEvent>, func: F) {
+    loop {
+        match tokio::time::timeout(IDLE_TIMEOUT, events.recv()).await {
+            Ok(Ok(event)) => func(&event),
+            Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+            Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => return,
+        }
+    }
+}
+
+#[tokio::test]
+async fn running_cli_commands() {
+    use crate::config::SimulatorConfig;
+
+    let hdmicec = HdmiCecProcess::new(&CecConfig {
+        simulator: Some(SimulatorConfig::default()),
+        ..CecConfig::default()
+    })
+    .expect("could not start simulator");
+    let mut events = hdmicec.subscribe();
+    hdmicec.listen().expect("could not listen");
+    connect(&hdmicec).await.expect("could not connect");
+
+    assert_eq!(
+        power_status(&hdmicec, &mut events)
+            .await
+            .expect("no power status"),
+        "OFF"
+    );
+    assert!(switch_source(&hdmicec, "HDMI 2").is_ok());
+    assert!(switch_source(&hdmicec, "5").is_err());
+    assert!(send(&hdmicec, &mut events, "zz").await.is_err());
+
+    let devices = scan(&hdmicec, &mut events).await.expect("could not scan");
+    let described = devices.iter().map(describe).collect::<Vec<_>>();
+    assert!(described.contains(&"5  Audio         1.0.0.0  Soundbar       OFF".to_string()));
+    assert_eq!(devices.len(), 3);
+}
@@ -0,0 +1,226 @@
+use serde::Deserialize;
+
+use crate::error::BridgeError;
+use crate::ha_entity::EntityClass;
+
+/// What to do with the power of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, strum_macros::Display)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum PowerCommand {
+    #[strum(to_string = "ON")]
+    On,
+    #[strum(to_string = "OFF")]
+    Off,
+    #[strum(to_string = "TOGGLE")]
+    Toggle,
+}
+
+/// A command from homeassistant, parsed from the payload on an entity's command topic. Payloads are either plain strings like "ON" or "PRESS", or JSON objects with any of the fields below, like {"source":"HDMI 2","power":"ON"}.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Command {
+    #[serde(default)]
+    pub power: Option<PowerCommand>,
+
+    /// the name of an input source, like "HDMI 2".
+    #[serde(default)]
+    pub source: Option<String>,
+
+    /// a button was pressed. Only ever set from the plain "PRESS" payload.
+    #[serde(skip)]
+    pub press: bool,
+}
+
+/// The payloads an entity accepts on its command topic. Anything else is rejected before it gets to the entity.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandSchema {
+    /// accepts "ON", "OFF" and "TOGGLE".
+    pub power: bool,
+    /// accepts "PRESS".
+    pub press: bool,
+    /// accepts only "ON", like a scene.
+    pub activate: bool,
+    /// the source names accepted in a JSON command's "source" field.
+    pub sources: Vec<String>,
+    /// what homeassistant sends instead of "ON", "OFF" and "PRESS", if it isn't those.
+    pub on_payload: Option<String>,
+    pub off_payload: Option<String>,
+    pub press_payload: Option<String>,
+}
+
+impl CommandSchema {
+    pub fn power() -> Self {
+        return Self {
+            power: true,
+            ..Self::default()
+        };
+    }
+
+    pub fn press() -> Self {
+        return Self {
+            press: true,
+            ..Self::default()
+        };
+    }
+
+    pub fn activate() -> Self {
+        return Self {
+            activate: true,
+            ..Self::default()
+        };
+    }
+
+    /// what homeassistant sends by default, for each kind of entity.
+    pub fn for_class(entity_class: &EntityClass) -> Self {
+        return match entity_class {
+            EntityClass::Switch(_) => Self::power(),
+            EntityClass::Button(_) => Self::press(),
+            EntityClass::Scene => Self::activate(),
+            _ => Self::default(),
+        };
+    }
+
+    pub fn with_sources(mut self, sources: Vec<String>) -> Self {
+        self.sources = sources;
+        return self;
+    }
+
+    /// the 1-based index of a source name, as used by CEC.
+    pub fn source_index(&self, source: &str) -> Option<usize> {
+        return self
+            .sources
+            .iter()
+            .position(|name| name == source)
+            .map(|index| index + 1);
+    }
+
+    pub fn payload_on(&self) -> Option<String> {
+        return (self.power || self.activate).then(|| {
+            self.on_payload
+                .clone()
+                .unwrap_or_else(|| PowerCommand::On.to_string())
+        });
+    }
+
+    pub fn payload_off(&self) -> Option<String> {
+        return self.power.then(|| {
+            self.off_payload
+                .clone()
+                .unwrap_or_else(|| PowerCommand::Off.to_string())
+        });
+    }
+
+    pub fn payload_press(&self) -> Option<String> {
+        return self
+            .press
+            .then(|| self.press_payload.clone().unwrap_or("PRESS".to_string()));
+    }
+
+    /// parse a payload for 'entity', and make sure it only asks for things this schema accepts.
+    pub fn parse(&self, entity: &str, payload: &str) -> Result<Command, BridgeError> {
+        let invalid = || BridgeError::InvalidCommand {
+            entity: entity.to_string(),
+            payload: payload.to_string(),
+        };
+
+        // custom payloads stand in for the ones homeassistant sends by default.
+        let payload = payload.trim();
+        let keyword = if self.on_payload.as_deref() == Some(payload) {
+            "ON"
+        } else if self.off_payload.as_deref() == Some(payload) {
+            "OFF"
+        } else if self.press_payload.as_deref() == Some(payload) {
+            "PRESS"
+        } else {
+            payload
+        };
+        let command = match keyword {
+            "ON" => Command {
+                power: Some(PowerCommand::On),
+                ..Command::default()
+            },
+            "OFF" => Command {
+                power: Some(PowerCommand::Off),
+                ..Command::default()
+            },
+            "TOGGLE" => Command {
+                power: Some(PowerCommand::Toggle),
+                ..Command::default()
+            },
+            "PRESS" => Command {
+                press: true,
+                ..Command::default()
+            },
+            json if json.starts_with('{') => serde_json::from_str(json).map_err(|_| invalid())?,
+            _ => return Err(invalid()),
+        };
+
+        let accepted = (command.power.is_some() || command.source.is_some() || command.press)
+            && (command.power.is_none()
+                || self.power
+                || (self.activate && command.power == Some(PowerCommand::On)))
+            && (!command.press || self.press)
+            && command
+                .source
+                .as_ref()
+                .is_none_or(|source| self.source_index(source).is_some());
+        if !accepted {
+            return Err(invalid());
+        }
+        return Ok(command);
+    }
+}
+
+#[test]
+fn parsing_commands() {
+    let schema =
+        CommandSchema::power().with_sources(vec!["HDMI 1".to_string(), "HDMI 2".to_string()]);
+
+    let command = schema.parse("tv", "ON").expect("ON is accepted");
+    assert_eq!(command.power, Some(PowerCommand::On));
+    let command = schema.parse("tv", "TOGGLE").expect("TOGGLE is accepted");
+    assert_eq!(command.power, Some(PowerCommand::Toggle));
+
+    let command = schema
+        .parse("tv", r#"{"source":"HDMI 2","power":"ON"}"#)
+        .expect("JSON is accepted");
+    assert_eq!(command.power, Some(PowerCommand::On));
+    assert_eq!(command.source.as_deref(), Some("HDMI 2"));
+    assert_eq!(schema.source_index("HDMI 2"), Some(2));
+
+    for payload in [
+        "on",
+        "PRESS",
+        "",
+        "{}",
+        r#"{"source":"HDMI 3"}"#,
+        r#"{"power":"SIDEWAYS"}"#,
+        r#"{"volume":10}"#,
+        "{not json",
+    ] {
+        assert!(
+            matches!(
+                schema.parse("tv", payload),
+                Err(BridgeError::InvalidCommand { .. })
+            ),
+            "{payload} should be rejected"
+        );
+    }
+
+    assert!(CommandSchema::press().parse("mute", "PRESS").is_ok());
+    assert!(CommandSchema::press().parse("mute", "ON").is_err());
+    assert!(CommandSchema::activate().parse("scene", "ON").is_ok());
+    assert!(CommandSchema::activate().parse("scene", "OFF").is_err());
+    assert_eq!(CommandSchema::activate().payload_off(), None);
+
+    let schema = CommandSchema {
+        on_payload: Some("1".to_string()),
+        off_payload: Some("0".to_string()),
+        ..CommandSchema::power()
+    };
+    assert_eq!(schema.payload_on().as_deref(), Some("1"));
+    let command = schema
+        .parse("tv", "0")
+        .expect("the custom payload is accepted");
+    assert_eq!(command.power, Some(PowerCommand::Off));
+}
@@ -1,27 +1,32 @@
-use rumqttc::{LastWill, MqttOptions, QoS};
+use anyhow::{anyhow, Context, Error};
+use log::info;
+use rumqttc::MqttOptions;
 use serde::Deserialize;
-use std::time::Duration;
+use std::{env, fmt::Display, fs, io, str::FromStr, time::Duration};
 
-#[derive(Debug, Clone, Deserialize)]
+use crate::cec_frame::{CecFrame, Direction};
+use crate::cec_simulator::parse_physical_address;
+
+/// the prefix for environment variables that override the config file, like HDMICEC2MQTT_MQTT__HOST.
+const ENV_PREFIX: &str = "HDMICEC2MQTT_";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct MqttCredentials {
     pub username: String,
+    #[serde(default)]
     pub password: String,
+    /// read the password from this file instead, like a docker secret. A trailing newline is ignored.
+    pub password_file: Option<String>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub enum MqttQos {
-    AtMostOnce,
-    AtLeastOnce,
-    ExactlyOnce,
-}
-
-impl Into<QoS> for MqttQos {
-    fn into(self) -> QoS {
-        match self {
-            Self::AtMostOnce => QoS::AtMostOnce,
-            Self::AtLeastOnce => QoS::AtLeastOnce,
-            Self::ExactlyOnce => QoS::ExactlyOnce,
+impl MqttCredentials {
+    fn read_password_file(&mut self) -> Result<(), Error> {
+        if let Some(path) = &self.password_file {
+            let password = fs::read_to_string(path)
+                .with_context(|| format!("could not read the mqtt password from \"{path}\""))?;
+            self.password = password.trim_end_matches(['\r', '\n']).to_string();
         }
+        return Ok(());
     }
 }
 
@@ -29,8 +34,444 @@ impl Into<QoS> for MqttQos {
 pub struct Config {
     /// configuration for the MQTT client. see the rumqttc docs for most of these options.
     pub mqtt: MqttConfig,
+    #[serde(default)]
     pub topic: TopicConfig,
+    #[serde(default)]
     pub device: DeviceConfig,
+    /// configuration for how cec-client is started. All of these are optional, and fall back to the libcec defaults.
+    #[serde(default)]
+    pub cec: CecConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    /// an optional HTTP listener, for prometheus metrics and health checks. Nothing listens unless this section is there.
+    pub http: Option<HttpConfig>,
+    /// lists of steps to run one after another, each added as a button or scene. These are [[macros]] sections in the config file.
+    #[serde(default)]
+    pub macros: Vec<MacroConfig>,
+}
+
+impl Config {
+    /// Read the config file at 'path', with overrides from HDMICEC2MQTT_ environment variables. The file doesn't have to exist, as long as the environment has everything.
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => Some(contents),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                info!("there is no config file at \"{path}\", so everything has to come from the environment");
+                None
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("could not read config file \"{path}\""))
+            }
+        };
+        let missing = contents.is_none();
+        // a variable that isn't unicode can't be one of ours, so it doesn't need to stop us.
+        let vars = env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        });
+        return Config::from_sources(contents.as_deref(), vars).with_context(|| {
+            if missing {
+                format!("there is no config file at \"{path}\", and the environment is missing something")
+            } else {
+                format!("invalid config, from \"{path}\" and the environment")
+            }
+        });
+    }
+
+    /// build a config from the contents of a config file, and environment variables. Each variable sets one field, with "__" between the names of the sections, like HDMICEC2MQTT_MQTT__CREDENTIALS__USERNAME.
+    fn from_sources(
+        contents: Option<&str>,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, Error> {
+        let mut table: toml::Table = toml::from_str(contents.unwrap_or(""))?;
+        for (name, value) in vars {
+            let path = match name.strip_prefix(ENV_PREFIX) {
+                Some(path) => path,
+                None => continue,
+            };
+            set_field(&mut table, path, env_value(&value))
+                .with_context(|| format!("invalid environment variable {name}"))?;
+        }
+
+        let mut config: Config = table.try_into()?;
+        if let Some(credentials) = config.mqtt.credentials.as_mut() {
+            credentials.read_password_file()?;
+        }
+        return Ok(config);
+    }
+}
+
+/// set a field like "MQTT__HOST" in 'table', adding any sections that aren't there yet.
+fn set_field(table: &mut toml::Table, path: &str, value: toml::Value) -> Result<(), Error> {
+    let keys: Vec<String> = path.split("__").map(|key| key.to_lowercase()).collect();
+    let (field, sections) = keys.split_last().expect("split always returns something");
+    let mut table = table;
+    for section in sections {
+        table = table
+            .entry(section.clone())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("\"{section}\" is not a section"))?;
+    }
+    table.insert(field.clone(), value);
+    return Ok(());
+}
+
+/// lists and tables are read as TOML, like they would be in the config file. Everything else is a plain string, and the fields that want a number or a boolean read it from that, see string_or().
+fn env_value(value: &str) -> toml::Value {
+    if value.starts_with(['[', '{']) {
+        let parsed = toml::from_str::<toml::Table>(&format!("value = {value}"))
+            .ok()
+            .and_then(|mut table| table.remove("value"));
+        if let Some(parsed) = parsed {
+            return parsed;
+        }
+    }
+    return toml::Value::String(value.to_string());
+}
+
+/// read a field that can also be given as a string, like "1883" for a port. Every environment variable is a string, and it's the field that knows what it wants.
+fn string_or<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::de::DeserializeOwned + FromStr,
+    T::Err: Display,
+{
+    return match toml::Value::deserialize(deserializer)? {
+        toml::Value::String(value) => value
+            .parse()
+            .map_err(|err| serde::de::Error::custom(format!("\"{value}\": {err}"))),
+        value => T::deserialize(value).map_err(serde::de::Error::custom),
+    };
+}
+
+/// string_or(), for a field that doesn't have to be there.
+fn optional_string_or<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::de::DeserializeOwned + FromStr,
+    T::Err: Display,
+{
+    return string_or(deserializer).map(Some);
+}
+
+/// Something wrong with a config value, that would only show up later on, if at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigProblem {
+    /// where the value is, like "device.unique_id".
+    pub field: String,
+    pub problem: String,
+    /// what to do about it.
+    pub suggestion: String,
+}
+
+impl Display for ConfigProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "{}: {}. {}", self.field, self.problem, self.suggestion);
+    }
+}
+
+impl Config {
+    /// Check for values that parse, but won't work. Every problem is returned, so they can all be fixed at once.
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        let mut problems = vec![];
+        let mut problem = |field: &str, problem: String, suggestion: String| {
+            problems.push(ConfigProblem {
+                field: field.to_string(),
+                problem,
+                suggestion,
+            });
+        };
+
+        if self.mqtt.host.trim().is_empty() {
+            problem(
+                "mqtt.host",
+                "is empty".to_string(),
+                "set it to your MQTT broker's IP or hostname".to_string(),
+            );
+        }
+        if self.mqtt.port == 0 {
+            problem(
+                "mqtt.port",
+                "is 0".to_string(),
+                "the default MQTT port is 1883".to_string(),
+            );
+        }
+        if !self.mqtt.keep_alive.is_finite() || self.mqtt.keep_alive < 1.0 {
+            problem(
+                "mqtt.keep_alive",
+                format!(
+                    "is {}, but has to be at least 1 second",
+                    self.mqtt.keep_alive
+                ),
+                "leave it out for the default of 5 seconds".to_string(),
+            );
+        }
+        if self.mqtt.async_capacity == 0 {
+            problem(
+                "mqtt.async_capacity",
+                "is 0, so nothing could ever be published".to_string(),
+                "leave it out for the default of 50".to_string(),
+            );
+        }
+        if self.mqtt.last_will.is_some() {
+            problem(
+                "mqtt.last_will",
+                "can't be set, as MQTT allows only one last will and it marks the bridge unavailable on the availability topic".to_string(),
+                "leave out the last_will section".to_string(),
+            );
+        }
+
+        for (field, id) in [
+            ("device.unique_id", Some(&self.device.unique_id)),
+            ("device.object_id", self.device.object_id.as_ref()),
+        ] {
+            let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+            match id {
+                Some(id) if id.is_empty() => problem(
+                    field,
+                    "is empty".to_string(),
+                    "pick a name like \"living_room_tv\"".to_string(),
+                ),
+                Some(id) if !id.chars().all(valid) => problem(
+                    field,
+                    format!("\"{id}\" has characters homeassistant doesn't allow in ids"),
+                    format!(
+                        "use only letters, numbers, '_' and '-', like \"{}\"",
+                        id.replace(|c: char| !valid(c), "_")
+                    ),
+                ),
+                _ => {}
+            }
+        }
+
+        for (field, topic) in [
+            ("topic.prefix", &self.topic.prefix),
+            ("topic.status", &self.topic.status),
+        ] {
+            if topic.is_empty() {
+                problem(
+                    field,
+                    "is empty".to_string(),
+                    "leave it out for the default".to_string(),
+                );
+            } else if topic.contains(['+', '#']) {
+                problem(
+                    field,
+                    format!("\"{topic}\" has MQTT wildcards in it"),
+                    "remove the '+' and '#'".to_string(),
+                );
+            } else if topic.starts_with('/') || topic.ends_with('/') {
+                problem(
+                    field,
+                    format!(
+                        "\"{topic}\" starts or ends with '/', which makes an empty topic level"
+                    ),
+                    format!("try \"{}\"", topic.trim_matches('/')),
+                );
+            }
+        }
+
+        if !self.shutdown.timeout.is_finite() || self.shutdown.timeout < 0.0 {
+            problem(
+                "shutdown.timeout",
+                format!("is {}", self.shutdown.timeout),
+                "it is in seconds, and the default is 5".to_string(),
+            );
+        }
+
+        if let Some(http) = &self.http {
+            // hostnames are fine too, but they are only looked up when we start listening.
+            let host_and_port = http
+                .listen
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+            if !host_and_port {
+                problem(
+                    "http.listen",
+                    format!("\"{}\" is not an address and port", http.listen),
+                    "use something like \"0.0.0.0:9464\"".to_string(),
+                );
+            }
+            if !http.max_poll_age.is_finite() {
+                problem(
+                    "http.max_poll_age",
+                    format!("is {}", http.max_poll_age),
+                    "leave it out for the default of 60 seconds".to_string(),
+                );
+            } else if http.max_poll_age < 10.0 {
+                problem(
+                    "http.max_poll_age",
+                    format!(
+                        "is {}, but the TV is only polled every 10 seconds",
+                        http.max_poll_age
+                    ),
+                    "leave it out for the default of 60 seconds".to_string(),
+                );
+            }
+        }
+
+        if self
+            .cec
+            .hdmi_port
+            .is_some_and(|port| !(1..=15).contains(&port))
+        {
+            problem(
+                "cec.hdmi_port",
+                "is out of range".to_string(),
+                "HDMI ports are numbered from 1 to 15".to_string(),
+            );
+        }
+        if self.cec.base_device.is_some_and(|address| address > 15) {
+            problem(
+                "cec.base_device",
+                "is out of range".to_string(),
+                "it is a logical address, from 0 (the TV) to 15".to_string(),
+            );
+        }
+        if let Some(replay) = &self.cec.replay {
+            if replay.speed.is_nan() || replay.speed <= 0.0 {
+                problem(
+                    "cec.replay.speed",
+                    format!("is {}", replay.speed),
+                    "use 1 for real time, or \"inf\" for everything at once".to_string(),
+                );
+            }
+        }
+        let mut macro_ids = std::collections::HashSet::new();
+        for (index, config) in self.macros.iter().enumerate() {
+            let field = format!("macros[{index}]");
+            let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+            if config.id.is_empty() || !config.id.chars().all(valid) {
+                problem(
+                    &format!("{field}.id"),
+                    format!("\"{}\" is not a valid id", config.id),
+                    "use only letters, numbers, '_' and '-', like \"movie_night\"".to_string(),
+                );
+            } else if !macro_ids.insert(&config.id) {
+                problem(
+                    &format!("{field}.id"),
+                    format!("\"{}\" is already used by another macro", config.id),
+                    "give each macro its own id".to_string(),
+                );
+            }
+            if config.steps.is_empty() {
+                problem(
+                    &format!("{field}.steps"),
+                    "is empty".to_string(),
+                    "add steps like { power = \"on\" }".to_string(),
+                );
+            }
+            if self.cec.monitor {
+                problem(
+                    &field,
+                    "can't run in monitor mode, which never sends anything on the bus".to_string(),
+                    "remove the macro, or turn off cec.monitor".to_string(),
+                );
+            }
+            for (step_index, step) in config.steps.iter().enumerate() {
+                let field = format!("{field}.steps[{step_index}]");
+                match step {
+                    MacroStep::Source(source) if !(1..=4).contains(source) => problem(
+                        &field,
+                        format!("source {source} is out of range"),
+                        "sources are numbered from 1 to 4".to_string(),
+                    ),
+                    MacroStep::Volume(level) if *level > 100 => problem(
+                        &field,
+                        format!("volume {level} is out of range"),
+                        "the volume goes from 0 to 100".to_string(),
+                    ),
+                    MacroStep::Send(frame) if CecFrame::parse(frame, Direction::Sent).is_none() => {
+                        problem(
+                            &field,
+                            format!("\"{frame}\" is not a CEC frame"),
+                            "use colon separated hex bytes, like \"10:8f\"".to_string(),
+                        )
+                    }
+                    MacroStep::Delay(seconds) if !seconds.is_finite() || *seconds < 0.0 => problem(
+                        &field,
+                        format!("delay {seconds} is not a number of seconds to wait"),
+                        "it is in seconds, like 1.5".to_string(),
+                    ),
+                    MacroStep::WaitFor(condition)
+                        if !condition.timeout.is_finite() || condition.timeout <= 0.0 =>
+                    {
+                        problem(
+                            &field,
+                            format!(
+                                "timeout {} is not a number of seconds to wait",
+                                condition.timeout
+                            ),
+                            "it is in seconds, and the default is 10".to_string(),
+                        )
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some(simulator) = &self.cec.simulator {
+            for (index, device) in simulator.devices.iter().enumerate() {
+                if device.logical_address >= 15 {
+                    problem(
+                        &format!("cec.simulator.devices[{index}].logical_address"),
+                        format!("{} is the broadcast address", device.logical_address),
+                        "use a logical address from 0 to 14".to_string(),
+                    );
+                }
+                if let Some(volume) = device.volume.filter(|volume| *volume > 100) {
+                    problem(
+                        &format!("cec.simulator.devices[{index}].volume"),
+                        format!("{volume} is out of range"),
+                        "the volume goes from 0 to 100".to_string(),
+                    );
+                }
+                if parse_physical_address(&device.physical_address).is_none() {
+                    problem(
+                        &format!("cec.simulator.devices[{index}].physical_address"),
+                        format!("\"{}\" is not a physical address", device.physical_address),
+                        "use something like \"1.0.0.0\"".to_string(),
+                    );
+                }
+            }
+        }
+        return problems;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct HttpConfig {
+    /// the address and port to listen on.
+    #[serde(default = "default_http_listen")]
+    pub listen: String,
+
+    /// how many seconds can pass without the TV answering a power poll, before we report ourselves as unhealthy. We poll every 10 seconds.
+    #[serde(default = "default_max_poll_age", deserialize_with = "string_or")]
+    pub max_poll_age: f64,
+
+    /// serve the REST API under /api, for controlling the TV without MQTT. Anyone who can reach the port can control the TV, so this is off by default.
+    #[serde(default, deserialize_with = "string_or")]
+    pub api: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShutdownConfig {
+    /// clear the retained state messages when shutting down, so homeassistant doesn't show stale states while we are gone.
+    #[serde(default, deserialize_with = "string_or")]
+    pub clear_state: bool,
+
+    /// how many seconds to wait for everything to shut down cleanly, before giving up. Docker waits 10 seconds before killing the container.
+    #[serde(default = "default_shutdown_timeout", deserialize_with = "string_or")]
+    pub timeout: f64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            clear_state: false,
+            timeout: default_shutdown_timeout(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -41,6 +482,20 @@ pub struct TopicConfig {
 
     #[serde(default = "default_status_topic")]
     pub status: String,
+
+    /// announce the device and all of its entities in one discovery message on "<prefix>/device/<object_id>/config", instead of one message per entity. This needs homeassistant 2024.11 or newer.
+    #[serde(default, deserialize_with = "string_or")]
+    pub device_discovery: bool,
+}
+
+impl Default for TopicConfig {
+    fn default() -> Self {
+        Self {
+            prefix: default_discovery_topic_prefix(),
+            status: default_status_topic(),
+            device_discovery: false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -54,35 +509,259 @@ pub struct DeviceConfig {
 
     /// The device name to use. By default, it defaults to the unique_id.
     pub device_name: Option<String>,
+
+    /// A file to keep the list of entities we announced in. This lets us remove the old entities from homeassistant after changing the unique_id or object_id.
+    pub state_file: Option<String>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct MqttLastWill {
-    pub topic: String,
-    pub message: String,
-    pub qos: MqttQos,
-    pub retain: bool,
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            unique_id: default_unique_id(),
+            object_id: None,
+            device_name: None,
+            state_file: None,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// The CEC device type to register as. Some TVs will switch inputs to a "recording" device (the libcec default) when it starts up, so picking another type can avoid that.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CecDeviceType {
+    Recording,
+    Playback,
+    Tuner,
+    AudioSystem,
+}
+
+impl CecDeviceType {
+    /// the value cec-client expects for its "-t" argument.
+    pub fn as_arg(&self) -> &'static str {
+        match self {
+            Self::Recording => "r",
+            Self::Playback => "p",
+            Self::Tuner => "t",
+            Self::AudioSystem => "a",
+        }
+    }
+
+    /// the first logical address libcec claims for this type, when it is free.
+    pub fn logical_address(&self) -> u8 {
+        match self {
+            Self::Recording => 1,
+            Self::Playback => 4,
+            Self::Tuner => 3,
+            Self::AudioSystem => 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+pub struct CecConfig {
+    /// The adapter port or COM path to connect to, like "/dev/cec0" or "RPI". cec-client will use the first adapter it finds if this is not set.
+    pub port: Option<String>,
+
+    /// The device type to register on the CEC bus as.
+    pub device_type: Option<CecDeviceType>,
+
+    /// The name other devices (like the TV) will show for us.
+    pub osd_name: Option<String>,
+
+    /// The HDMI port on the base device that we are connected to. Together with `base_device`, this determines our physical address.
+    #[serde(default, deserialize_with = "optional_string_or")]
+    pub hdmi_port: Option<u8>,
+
+    /// The logical address of the device we are connected to. This is usually the TV (0), unless we are plugged into an AV receiver or switch.
+    #[serde(default, deserialize_with = "optional_string_or")]
+    pub base_device: Option<u8>,
+
+    /// Start cec-client as a monitor-only client, which does not claim a logical address on the bus.
+    #[serde(default, deserialize_with = "string_or")]
+    pub monitor: bool,
+
+    /// Write every line sent to, and read from cec-client to this file, with timestamps. Useful for debugging.
+    pub capture_file: Option<String>,
+
+    /// Instead of starting cec-client, replay the output from a capture file.
+    pub replay: Option<ReplayConfig>,
+
+    /// Instead of starting cec-client, talk to a simulated CEC bus. Useful for trying things out without a TV.
+    pub simulator: Option<SimulatorConfig>,
+}
+
+/// A list of steps to run one after another, like turning the TV on, waiting for it, and switching sources. Each macro is added to homeassistant as a button or a scene, along with a sensor for its progress.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MacroConfig {
+    /// used in the entity ids, like "movie_night".
+    pub id: String,
+
+    /// the name homeassistant shows. By default, this is the id.
+    pub name: Option<String>,
+
+    /// whether to add the macro as a button, or a scene.
+    #[serde(default)]
+    pub platform: MacroPlatform,
+
+    /// an icon like "mdi:movie-open", instead of the default.
+    pub icon: Option<String>,
+
+    pub steps: Vec<MacroStep>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MacroPlatform {
+    #[default]
+    Button,
+    Scene,
+}
+
+/// One step of a macro. Each is written with its name as the key, like { source = 2 }, except for "mute", which is just the name.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MacroStep {
+    /// turn the TV on or off.
+    Power(PowerState),
+    /// wait for the TV to report a power state, polling it until then.
+    WaitFor(WaitCondition),
+    /// switch to an input source, by its number.
+    Source(usize),
+    /// ask the audio system to play the sound of the active source, or to stop.
+    SystemAudio(PowerState),
+    /// set the audio system's volume, from 0 to 100. Only CEC 2.0 audio systems understand this.
+    Volume(u8),
+    /// press volume up this many times.
+    VolumeUp(u8),
+    /// press volume down this many times.
+    VolumeDown(u8),
+    Mute,
+    /// send a raw frame, like "10:8f".
+    Send(String),
+    /// wait this many seconds.
+    Delay(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, strum_macros::Display)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerState {
+    #[strum(to_string = "ON")]
+    On,
+    #[strum(to_string = "OFF")]
+    Off,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WaitCondition {
+    pub power: PowerState,
+
+    /// how many seconds to wait, before the macro fails.
+    #[serde(default = "default_wait_timeout")]
+    pub timeout: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SimulatorConfig {
+    /// the devices on the simulated bus. By default, this is a TV, a soundbar, and a player.
+    #[serde(default = "default_simulated_devices")]
+    pub devices: Vec<SimulatedDeviceConfig>,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            devices: default_simulated_devices(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SimulatedDeviceConfig {
+    pub logical_address: u8,
+    /// formatted like "1.0.0.0".
+    pub physical_address: String,
+    pub osd_name: String,
+    /// whether the device starts out powered on.
+    #[serde(default)]
+    pub power: bool,
+    /// the starting volume, from 0 to 100. Only audio systems should set this.
+    pub volume: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ReplayConfig {
+    /// the capture file to replay.
+    pub file: String,
+
+    /// how much faster than real time to replay the capture. "inf" replays everything at once.
+    #[serde(default = "default_replay_speed", deserialize_with = "string_or")]
+    pub speed: f64,
+}
+
+impl CecConfig {
+    /// the arguments to pass on to cec-client. This does not include the log level, which is set by the process itself.
+    pub fn as_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = vec![];
+
+        self.device_type.as_ref().map(|device_type| {
+            args.push("-t".to_string());
+            args.push(device_type.as_arg().to_string());
+        });
+        self.osd_name.as_ref().map(|osd_name| {
+            args.push("-o".to_string());
+            args.push(osd_name.clone());
+        });
+        self.hdmi_port.as_ref().map(|hdmi_port| {
+            args.push("-p".to_string());
+            args.push(hdmi_port.to_string());
+        });
+        self.base_device.as_ref().map(|base_device| {
+            args.push("-b".to_string());
+            args.push(base_device.to_string());
+        });
+        if self.monitor {
+            args.push("-m".to_string());
+        }
+        // the port is positional, and always needs to come last.
+        self.port.as_ref().map(|port| {
+            args.push(port.clone());
+        });
+
+        return args;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct MqttConfig {
     pub host: String,
+    #[serde(deserialize_with = "string_or")]
     pub port: u16,
     #[serde(default = "default_device_id")]
     pub deviceid: String,
-    #[serde(default = "default_keep_alive")]
+    #[serde(default = "default_keep_alive", deserialize_with = "string_or")]
     pub keep_alive: f64,
-    /// the size of the bounded async channel the client is started with. **NOTE Setting this too low can cause the entire process to hang unexpectedly!!**
-    #[serde(default = "default_async_capacity")]
+    /// the size of the bounded async channel the client is started with. Publishing waits for room in this channel, so setting it too low can slow down discovery and state updates.
+    #[serde(default = "default_async_capacity", deserialize_with = "string_or")]
     pub async_capacity: usize,
+    #[serde(default, deserialize_with = "optional_string_or")]
     pub max_packet_size: Option<usize>,
+    #[serde(default, deserialize_with = "optional_string_or")]
     pub clean_session: Option<bool>,
     pub credentials: Option<MqttCredentials>,
+    #[serde(default, deserialize_with = "optional_string_or")]
     pub request_channel_capacity: Option<usize>,
+    #[serde(default, deserialize_with = "optional_string_or")]
     pub pending_throttle: Option<f64>,
+    #[serde(default, deserialize_with = "optional_string_or")]
     pub inflight: Option<u16>,
+    #[serde(default, deserialize_with = "optional_string_or")]
     pub manual_acks: Option<bool>,
-    pub last_will: Option<MqttLastWill>,
+    /// only read so validation can turn it down: the last will is always the availability topic.
+    pub last_will: Option<toml::Table>,
+    /// publish entity states as retained messages, so homeassistant has them straight away after it restarts. They are read back when we start up, along with the list of entities we added last time.
+    #[serde(default, deserialize_with = "string_or")]
+    pub retain_state: bool,
 }
 
 impl MqttConfig {
@@ -112,20 +791,56 @@ impl MqttConfig {
             mqtt_options.set_manual_acks(*value);
         });
 
-        // TODO we will want to make a last will message, even if not configured.
-        self.last_will.as_ref().map(|last_will| {
-            mqtt_options.set_last_will(LastWill {
-                topic: last_will.topic.clone(),
-                message: last_will.message.clone().into(),
-                qos: last_will.qos.clone().into(),
-                retain: last_will.retain,
-            });
-        });
-
         return mqtt_options;
     }
 }
 
+fn default_simulated_devices() -> Vec<SimulatedDeviceConfig> {
+    return vec![
+        SimulatedDeviceConfig {
+            logical_address: 0,
+            physical_address: "0.0.0.0".to_string(),
+            osd_name: "TV".to_string(),
+            power: false,
+            volume: None,
+        },
+        SimulatedDeviceConfig {
+            logical_address: 5,
+            physical_address: "1.0.0.0".to_string(),
+            osd_name: "Soundbar".to_string(),
+            power: false,
+            volume: Some(20),
+        },
+        SimulatedDeviceConfig {
+            logical_address: 4,
+            physical_address: "2.0.0.0".to_string(),
+            osd_name: "Player".to_string(),
+            power: false,
+            volume: None,
+        },
+    ];
+}
+
+fn default_shutdown_timeout() -> f64 {
+    return 5.0;
+}
+
+fn default_wait_timeout() -> f64 {
+    return 10.0;
+}
+
+fn default_http_listen() -> String {
+    return "0.0.0.0:9464".to_string();
+}
+
+fn default_max_poll_age() -> f64 {
+    return 60.0;
+}
+
+fn default_replay_speed() -> f64 {
+    return 1.0;
+}
+
 fn default_device_id() -> String {
     return "hdmi_cec_proxy".to_string();
 }
@@ -149,3 +864,227 @@ fn default_status_topic() -> String {
 fn default_unique_id() -> String {
     return "hdmi_device".to_string();
 }
+
+#[test]
+fn cec_client_arguments() {
+    let config: CecConfig = toml::from_str(
+        r#"
+        port = "/dev/cec0"
+        device_type = "playback"
+        osd_name = "Home Assistant"
+        hdmi_port = 2
+        base_device = 5
+        monitor = true
+        "#,
+    )
+    .expect("could not parse cec config");
+
+    assert_eq!(
+        config.as_args(),
+        vec![
+            "-t",
+            "p",
+            "-o",
+            "Home Assistant",
+            "-p",
+            "2",
+            "-b",
+            "5",
+            "-m",
+            "/dev/cec0"
+        ]
+    );
+    assert!(CecConfig::default().as_args().is_empty());
+}
+
+#[test]
+fn configuring_from_the_environment() {
+    let vars = |vars: &[(&str, &str)]| {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+    };
+    let config = Config::from_sources(
+        Some(
+            r#"
+            [mqtt]
+            host = "localhost"
+            port = 1883
+            [topic]
+            [device]
+            "#,
+        ),
+        vars(&[
+            ("HDMICEC2MQTT_MQTT__HOST", "broker.lan"),
+            ("HDMICEC2MQTT_MQTT__PORT", "8883"),
+            ("HDMICEC2MQTT_CEC__MONITOR", "true"),
+            ("HDMICEC2MQTT_DEVICE__UNIQUE_ID", "1234"),
+            ("HDMICEC2MQTT_DEVICE__DEVICE_NAME", "5678"),
+            ("HDMICEC2MQTT_MQTT__KEEP_ALIVE", "2.5"),
+            ("HDMICEC2MQTT_MQTT__INFLIGHT", "10"),
+            ("HDMICEC2MQTT_MQTT__CREDENTIALS__USERNAME", "hdmicec"),
+            ("HDMICEC2MQTT_MQTT__CREDENTIALS__PASSWORD", "123456"),
+            ("HDMICEC2MQTT_HTTP__LISTEN", "127.0.0.1:9464"),
+            ("PATH", "/usr/bin"),
+        ]),
+    )
+    .expect("invalid config");
+    assert_eq!(config.mqtt.host, "broker.lan");
+    assert_eq!(config.mqtt.port, 8883);
+    assert_eq!(config.mqtt.keep_alive, 2.5);
+    assert_eq!(config.mqtt.inflight, Some(10));
+    assert!(config.cec.monitor);
+    assert_eq!(config.device.unique_id, "1234");
+    assert_eq!(config.device.device_name.as_deref(), Some("5678"));
+    assert_eq!(
+        config.mqtt.credentials.expect("no credentials").password,
+        "123456"
+    );
+    assert_eq!(
+        config.http.expect("no http section").listen,
+        "127.0.0.1:9464"
+    );
+
+    // no file at all works too, as long as the broker is there. Everything else has a default.
+    let password_file = crate::test_harness::temp_path("configuring_from_the_environment.txt");
+    fs::write(&password_file, "hunter2\n").expect("could not write password file");
+    let config = Config::from_sources(
+        None,
+        vars(&[
+            ("HDMICEC2MQTT_MQTT__HOST", "broker.lan"),
+            ("HDMICEC2MQTT_MQTT__PORT", "1883"),
+            ("HDMICEC2MQTT_MQTT__CREDENTIALS__USERNAME", "hdmicec"),
+            (
+                "HDMICEC2MQTT_MQTT__CREDENTIALS__PASSWORD_FILE",
+                password_file.to_str().expect("invalid path"),
+            ),
+        ]),
+    )
+    .expect("invalid config");
+    assert_eq!(
+        config.mqtt.credentials.expect("no credentials").password,
+        "hunter2"
+    );
+
+    assert!(
+        Config::from_sources(None, vars(&[("HDMICEC2MQTT_MQTT__HOST", "broker.lan")])).is_err()
+    );
+    assert!(Config::from_sources(
+        Some("[mqtt]\nhost = \"localhost\""),
+        vars(&[("HDMICEC2MQTT_MQTT__HOST__NAME", "broker.lan")])
+    )
+    .is_err());
+    assert!(Config::from_sources(
+        None,
+        vars(&[
+            ("HDMICEC2MQTT_MQTT__HOST", "broker.lan"),
+            ("HDMICEC2MQTT_MQTT__PORT", "lots")
+        ])
+    )
+    .is_err());
+}
+
+#[test]
+fn validating_configs() {
+    let config: Config = toml::from_str(
+        r#"
+        [mqtt]
+        host = "localhost"
+        port = 1883
+        [device]
+        unique_id = "living room"
+        "#,
+    )
+    .expect("invalid config");
+    let problems = config.validate();
+    assert_eq!(problems.len(), 1);
+    assert_eq!(
+        problems[0].to_string(),
+        r#"device.unique_id: "living room" has characters homeassistant doesn't allow in ids. use only letters, numbers, '_' and '-', like "living_room""#
+    );
+
+    let config: Config = toml::from_str(
+        r#"
+        [mqtt]
+        host = "localhost"
+        port = 1883
+        keep_alive = 0
+        [shutdown]
+        timeout = inf
+        [mqtt.last_will]
+        topic = "homeassistant/switch/hdmi_device_tv/state"
+        message = "offline"
+        qos = "AtLeastOnce"
+        retain = true
+        [topic]
+        status = "homeassistant/status/"
+        [http]
+        listen = "9464"
+        "#,
+    )
+    .expect("invalid config");
+    let fields: Vec<String> = config
+        .validate()
+        .into_iter()
+        .map(|problem| problem.field)
+        .collect();
+    assert_eq!(
+        fields,
+        vec![
+            "mqtt.keep_alive",
+            "mqtt.last_will",
+            "topic.status",
+            "shutdown.timeout",
+            "http.listen"
+        ]
+    );
+
+    let config: Config = toml::from_str(
+        r#"
+        [mqtt]
+        host = "localhost"
+        port = 1883
+        [[macros]]
+        id = "movie_night"
+        steps = [{ source = 5 }, { send = "zz" }, { wait_for = { power = "on", timeout = 0 } }, { delay = inf }]
+        [[macros]]
+        id = "movie_night"
+        steps = []
+        [[cec.simulator.devices]]
+        logical_address = 5
+        physical_address = "1.1.0.0"
+        osd_name = "Soundbar"
+        volume = 250
+        "#,
+    )
+    .expect("invalid config");
+    let fields: Vec<String> = config
+        .validate()
+        .into_iter()
+        .map(|problem| problem.field)
+        .collect();
+    assert_eq!(
+        fields,
+        vec![
+            "macros[0].steps[0]",
+            "macros[0].steps[1]",
+            "macros[0].steps[2]",
+            "macros[0].steps[3]",
+            "macros[1].id",
+            "macros[1].steps",
+            "cec.simulator.devices[0].volume"
+        ]
+    );
+
+    let config: Config = toml::from_str(
+        r#"
+        [mqtt]
+        host = "localhost"
+        port = 1883
+        [http]
+        listen = "localhost:9464"
+        "#,
+    )
+    .expect("invalid config");
+    assert!(config.validate().is_empty());
+}
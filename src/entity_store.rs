@@ -0,0 +1,72 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind},
+};
+
+use crate::payloads::EntityListPayload;
+
+/// Keeps a list of every entity we announced in a local file. Unlike the retained entity list, this survives changing the device's unique_id or object_id, so we can still clean up after the old ones.
+pub struct EntityStore {
+    path: String,
+}
+
+impl EntityStore {
+    pub fn new(path: &str) -> Self {
+        return Self {
+            path: path.to_string(),
+        };
+    }
+
+    /// read the entities we announced last time. A missing file just means we haven't announced anything yet.
+    pub fn load(&self) -> Result<EntityListPayload, Error> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Ok(EntityListPayload::default())
+            }
+            Err(err) => return Err(err),
+        };
+        return serde_json::from_str(&contents).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{}: invalid entity list: {err}", self.path),
+            )
+        });
+    }
+
+    pub fn save(&self, entities: &EntityListPayload) -> Result<(), Error> {
+        let contents = serde_json::to_string_pretty(entities)?;
+        return fs::write(&self.path, contents);
+    }
+
+    pub fn remove(&self) -> Result<(), Error> {
+        return match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        };
+    }
+}
+
+#[test]
+fn storing_entities() {
+    use crate::payloads::EntityRecordPayload;
+
+    let path = crate::test_harness::temp_path("storing_entities.json");
+    let store = EntityStore::new(path.to_str().expect("temp dir is not utf-8"));
+    store.remove().expect("could not remove old store");
+    assert_eq!(
+        store.load().expect("could not load missing store"),
+        EntityListPayload::default()
+    );
+
+    let entities = EntityListPayload {
+        entities: vec![EntityRecordPayload {
+            discovery_topic: "homeassistant/switch/old_tv/config".to_string(),
+            state_topic: Some("homeassistant/switch/old_tv/state".to_string()),
+        }],
+        device_discovery: true,
+        removed: vec!["homeassistant/switch/older_tv/config".to_string()],
+    };
+    store.save(&entities).expect("could not save store");
+    assert_eq!(store.load().expect("could not load store"), entities);
+}
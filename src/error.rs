@@ -0,0 +1,45 @@
+use std::{io, string::FromUtf8Error};
+
+use rumqttc::ClientError;
+use thiserror::Error;
+
+/// Everything that can go wrong while passing messages between mqtt and cec-client. None of these should take the bridge down: they get logged, and reported on the device's error topic.
+#[derive(Debug, Error)]
+pub enum BridgeError {
+    #[error("payload on \"{topic}\" is not valid utf-8: {source}")]
+    InvalidPayload {
+        topic: String,
+        source: FromUtf8Error,
+    },
+
+    #[error("\"{payload}\" is not a valid command for \"{entity}\"")]
+    InvalidCommand { entity: String, payload: String },
+
+    #[error("could not publish to \"{topic}\": {source}")]
+    Publish { topic: String, source: ClientError },
+
+    #[error("could not subscribe to \"{topic}\": {source}")]
+    Subscribe { topic: String, source: ClientError },
+
+    #[error("could not start \"{program}\": {source}")]
+    ProcessStart { program: String, source: io::Error },
+
+    #[error("could not open capture file \"{path}\": {source}")]
+    Capture { path: String, source: io::Error },
+
+    #[error("the cec-client process is not running anymore")]
+    ProcessStopped,
+
+    #[error("the process output has already been taken")]
+    OutputTaken,
+
+    #[error("macro \"{name}\" is already running")]
+    MacroRunning { name: String },
+
+    #[error("macro \"{name}\" failed at step {step}: {reason}")]
+    MacroFailed {
+        name: String,
+        step: usize,
+        reason: String,
+    },
+}
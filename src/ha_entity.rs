@@ -1,10 +1,17 @@
 use std::string::ToString;
 
+use rumqttc::QoS;
+
+use crate::command::{Command, CommandSchema};
 use crate::config::Config;
-use crate::payloads::ConfigPayload;
+use crate::device_class::{BinarySensorClass, ButtonClass, NumberClass, SensorClass, SwitchClass};
+use crate::error::BridgeError;
+use crate::payloads::{
+    AvailabilityPayload, ConfigPayload, DiscoveryOptions, EntityCategory, StateClass,
+};
 use crate::service::StateManager;
 
-pub trait HaMqttEntity {
+pub trait HaMqttEntity: Send + Sync {
     fn get_config_payload(&self) -> ConfigPayload;
     fn get_discovery_topic(&self) -> String;
     fn get_state_topic(&self) -> Option<String>;
@@ -12,51 +19,65 @@ pub trait HaMqttEntity {
     #[allow(dead_code)] // for now, we don't use it, but keep it around for now.
     fn get_device(&self) -> Device;
     fn get_name(&self) -> String;
-    fn on_command(&mut self, payload: &str);
+    fn on_command(&mut self, payload: &str) -> Result<(), BridgeError>;
     fn connect_state(&mut self, state: StateManager);
+    /// called with the retained state from our last run, before any new state is published.
+    fn restore_state(&self, state: &str);
 }
 
+/// The entity platform, along with the device class for it, if any. This displays as the platform name, like "binary_sensor".
 #[derive(strum_macros::Display, Eq, PartialEq)]
 #[allow(dead_code)]
 pub enum EntityClass {
     #[strum(to_string = "switch")]
-    Switch,
+    Switch(Option<SwitchClass>),
     #[strum(to_string = "button")]
-    Button,
+    Button(Option<ButtonClass>),
     #[strum(to_string = "sensor")]
-    Sensor,
+    Sensor(Option<SensorClass>),
     #[strum(to_string = "binary_sensor")]
-    BinarySensor,
+    BinarySensor(Option<BinarySensorClass>),
+    #[strum(to_string = "number")]
+    Number(Option<NumberClass>),
+    #[strum(to_string = "scene")]
+    Scene,
 }
 
-#[derive(strum_macros::Display, PartialEq, Eq)]
-#[allow(dead_code)]
-pub enum DeviceClass {
-    #[strum(to_string = "switch")]
-    Switch,
-    #[strum(to_string = "motion")]
-    Motion,
-    #[strum(to_string = "none")]
-    None,
+impl EntityClass {
+    /// the device class, as homeassistant names it.
+    pub fn device_class(&self) -> Option<String> {
+        return match self {
+            Self::Switch(class) => class.map(|class| class.to_string()),
+            Self::Button(class) => class.map(|class| class.to_string()),
+            Self::Sensor(class) => class.map(|class| class.to_string()),
+            Self::BinarySensor(class) => class.map(|class| class.to_string()),
+            Self::Number(class) => class.map(|class| class.to_string()),
+            Self::Scene => None,
+        };
+    }
 }
 
-pub trait Commandable {
-    fn on_command(&mut self, payload: &str);
+pub trait Commandable: Send + Sync {
+    fn on_command(&mut self, command: &Command) -> Result<(), BridgeError>;
 }
 
+type CommandFn = dyn Fn(&Command) -> Result<(), BridgeError> + Send + Sync;
+
 pub struct SimpleCommand {
-    on_command: Box<dyn Fn(&str) -> ()>,
+    on_command: Box<CommandFn>,
 }
 impl SimpleCommand {
-    pub fn new<T: 'static + Fn(&str) -> ()>(on_command: T) -> Self {
+    pub fn new<T: 'static + Fn(&Command) -> Result<(), BridgeError> + Send + Sync>(
+        on_command: T,
+    ) -> Self {
         Self {
             on_command: Box::new(on_command),
         }
     }
 }
 impl Commandable for SimpleCommand {
-    fn on_command(&mut self, payload: &str) {
-        (self.on_command)(payload);
+    fn on_command(&mut self, command: &Command) -> Result<(), BridgeError> {
+        return (self.on_command)(command);
     }
 }
 
@@ -78,28 +99,63 @@ impl Device {
         }
     }
 
-    pub fn entity(&self, id: &str, entity_class: EntityClass, device_class: DeviceClass) -> Entity {
+    fn device_topic(&self, name: &str) -> String {
+        let prefix = &self.topic_prefix;
+        let object_id = self.object_id.as_ref().unwrap_or(&self.unique_id);
+        return format!("{prefix}/{object_id}/{name}");
+    }
+
+    /// the topic for announcing the whole device at once, when using device based discovery.
+    pub fn discovery_topic(&self) -> String {
+        let prefix = &self.topic_prefix;
+        let object_id = self.object_id.as_ref().unwrap_or(&self.unique_id);
+        return format!("{prefix}/device/{object_id}/config");
+    }
+
+    /// the topic we publish "online" or "offline" to, for every entity on this device.
+    pub fn availability_topic(&self) -> String {
+        return self.device_topic("availability");
+    }
+
+    /// the topic we report anything that went wrong handling a command to.
+    pub fn error_topic(&self) -> String {
+        return self.device_topic("error");
+    }
+
+    /// the topic we keep a retained list of our entities on, so we can tell which ones went away after an upgrade.
+    pub fn entities_topic(&self) -> String {
+        return self.device_topic("entities");
+    }
+
+    pub fn entity(&self, id: &str, entity_class: EntityClass) -> Entity {
         Entity {
             name: id.to_string(),
             topic_prefix: Entity::topic_prefix(self, id, &entity_class),
+            schema: CommandSchema::for_class(&entity_class),
+            options: DiscoveryOptions::default(),
             entity_class,
-            device_class,
             device: self.clone(),
             stateful: None,
+            restore: None,
             commands: None,
         }
     }
 }
 
+type RestoreFn = dyn Fn(&str) -> () + Send + Sync;
+
 pub struct Entity {
     pub name: String,
     pub topic_prefix: String,
-    #[allow(dead_code)]
     pub entity_class: EntityClass,
-    pub device_class: DeviceClass,
     pub device: Device,
+    /// the command payloads we accept. Defaults to what homeassistant sends for the entity class.
+    pub schema: CommandSchema,
+    /// everything else in the discovery payload. These are set with the builder methods below.
+    pub options: DiscoveryOptions,
     commands: Option<Box<dyn Commandable>>,
-    stateful: Option<Box<dyn Fn(StateManager) -> ()>>,
+    stateful: Option<Box<dyn Fn(StateManager) -> () + Send + Sync>>,
+    restore: Option<Box<RestoreFn>>,
 }
 
 impl Entity {
@@ -110,25 +166,149 @@ impl Entity {
         return format!("{prefix}/{class_str}/{object_id}_{name}");
     }
 
-    pub fn with_state<F: 'static + Fn(StateManager) -> ()>(mut self, func: F) -> Self {
+    pub fn with_state<F: 'static + Fn(StateManager) -> () + Send + Sync>(
+        mut self,
+        func: F,
+    ) -> Self {
         self.stateful = Some(Box::new(func));
         return self;
     }
 
+    /// call 'func' with the state we retained last time, if there is one. Only useful together with with_state().
+    pub fn with_restore<F: 'static + Fn(&str) -> () + Send + Sync>(mut self, func: F) -> Self {
+        self.restore = Some(Box::new(func));
+        return self;
+    }
+
+    pub fn with_schema(mut self, schema: CommandSchema) -> Self {
+        self.schema = schema;
+        return self;
+    }
+
     pub fn with_commands<T: 'static + Commandable>(mut self, commands: T) -> Self {
         self.commands = Some(Box::new(commands));
         return self;
     }
 }
 
+/// Builder methods for the optional parts of the discovery payload.
+impl Entity {
+    /// an icon like "mdi:television", instead of the default for the device class.
+    pub fn with_icon(mut self, icon: &str) -> Self {
+        self.options.icon = Some(icon.to_string());
+        return self;
+    }
+
+    /// the name homeassistant shows, instead of the id.
+    pub fn with_display_name(mut self, name: &str) -> Self {
+        self.options.name = Some(name.to_string());
+        return self;
+    }
+
+    pub fn with_entity_category(mut self, category: EntityCategory) -> Self {
+        self.options.entity_category = Some(category);
+        return self;
+    }
+
+    pub fn enabled_by_default(mut self, enabled: bool) -> Self {
+        self.options.enabled_by_default = Some(enabled);
+        return self;
+    }
+
+    /// read extra attributes for the entity from a JSON message on 'topic'. 'template' can pick the attributes out of it.
+    #[allow(dead_code)]
+    pub fn with_json_attributes(mut self, topic: &str, template: Option<&str>) -> Self {
+        self.options.json_attributes_topic = Some(topic.to_string());
+        self.options.json_attributes_template = template.map(|template| template.to_string());
+        return self;
+    }
+
+    /// only show the entity as available when 'availability' says so too, on top of the device's own availability.
+    #[allow(dead_code)]
+    pub fn with_availability(mut self, availability: AvailabilityPayload) -> Self {
+        self.options.availability.push(availability);
+        return self;
+    }
+
+    /// the QoS homeassistant publishes commands with.
+    #[allow(dead_code)]
+    pub fn with_qos(mut self, qos: QoS) -> Self {
+        self.options.qos = Some(qos as u8);
+        return self;
+    }
+
+    /// what homeassistant sends to turn the entity on and off, instead of "ON" and "OFF". Commands are read with the same payloads, so call this after with_schema().
+    #[allow(dead_code)]
+    pub fn with_power_payloads(mut self, on: &str, off: &str) -> Self {
+        self.schema.on_payload = Some(on.to_string());
+        self.schema.off_payload = Some(off.to_string());
+        return self;
+    }
+
+    /// what homeassistant sends when the button is pressed, instead of "PRESS". Like with_power_payloads(), call this after with_schema().
+    #[allow(dead_code)]
+    pub fn with_press_payload(mut self, press: &str) -> Self {
+        self.schema.press_payload = Some(press.to_string());
+        return self;
+    }
+
+    /// whether homeassistant retains the commands it publishes.
+    #[allow(dead_code)]
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.options.retain = Some(retain);
+        return self;
+    }
+
+    /// whether homeassistant assumes a command worked, instead of waiting for the state to change.
+    #[allow(dead_code)]
+    pub fn optimistic(mut self, optimistic: bool) -> Self {
+        self.options.optimistic = Some(optimistic);
+        return self;
+    }
+
+    /// the state payloads that mean on and off, when they differ from the command payloads.
+    #[allow(dead_code)]
+    pub fn with_state_values(mut self, state_on: &str, state_off: &str) -> Self {
+        self.options.state_on = Some(state_on.to_string());
+        self.options.state_off = Some(state_off.to_string());
+        return self;
+    }
+
+    #[allow(dead_code)]
+    pub fn with_unit(mut self, unit: &str) -> Self {
+        self.options.unit_of_measurement = Some(unit.to_string());
+        return self;
+    }
+
+    pub fn with_state_class(mut self, state_class: StateClass) -> Self {
+        self.options.state_class = Some(state_class);
+        return self;
+    }
+
+    /// mark the state as unavailable if it isn't updated for 'seconds'.
+    #[allow(dead_code)]
+    pub fn expire_after(mut self, seconds: u64) -> Self {
+        self.options.expire_after = Some(seconds);
+        return self;
+    }
+
+    #[allow(dead_code)]
+    pub fn with_value_template(mut self, template: &str) -> Self {
+        self.options.value_template = Some(template.to_string());
+        return self;
+    }
+}
+
 impl HaMqttEntity for Entity {
     fn get_config_payload(&self) -> ConfigPayload {
         return ConfigPayload::new(
             self.get_state_topic(),
             self.get_command_topic(),
             &self.device,
-            &self.device_class,
+            self.entity_class.device_class(),
             &self.name,
+            self.commands.as_ref().map(|_| &self.schema),
+            &self.options,
         );
     }
 
@@ -163,10 +343,11 @@ impl HaMqttEntity for Entity {
         }
     }
 
-    fn on_command(&mut self, payload: &str) {
-        if let Some(command) = self.commands.as_mut() {
-            command.on_command(payload);
-        }
+    fn on_command(&mut self, payload: &str) -> Result<(), BridgeError> {
+        return match self.commands.as_mut() {
+            Some(commands) => commands.on_command(&self.schema.parse(&self.name, payload)?),
+            None => Ok(()),
+        };
     }
 
     fn connect_state(&mut self, state: StateManager) {
@@ -174,4 +355,10 @@ impl HaMqttEntity for Entity {
             (state_listener)(state)
         }
     }
+
+    fn restore_state(&self, state: &str) {
+        if let Some(restore) = &self.restore {
+            (restore)(state)
+        }
+    }
 }
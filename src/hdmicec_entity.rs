@@ -1,47 +1,225 @@
-use log::{debug, info, log_enabled, trace};
-use std::process::Command;
+use log::{debug, error, info, log_enabled, trace, warn};
+use std::future::Future;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
 
+use crate::cec_diagnostics::{transmit_failed, CecDiagnostics};
+use crate::cec_frame::{CecFrame, UserControl};
+use crate::cec_simulator::{parse_physical_address, CecSimulator};
+use crate::command::Command as EntityCommand;
+use crate::config::CecConfig;
+use crate::error::BridgeError;
 use crate::ha_entity::SimpleCommand;
+use crate::metrics::metrics;
 use crate::process::CommandProcess;
 use crate::service::StateManager;
 
 pub trait ClonableHdmiCecProcess {
-    fn command<F: 'static + Fn(&HdmiCecProcess, &str) -> ()>(&self, func: F) -> SimpleCommand;
+    fn command<
+        F: 'static + Fn(&HdmiCecProcess, &EntityCommand) -> Result<(), BridgeError> + Send + Sync,
+    >(
+        &self,
+        entity: &str,
+        func: F,
+    ) -> SimpleCommand;
 }
 
 impl ClonableHdmiCecProcess for Arc<HdmiCecProcess> {
-    fn command<F: 'static + Fn(&HdmiCecProcess, &str) -> ()>(&self, func: F) -> SimpleCommand {
+    fn command<
+        F: 'static + Fn(&HdmiCecProcess, &EntityCommand) -> Result<(), BridgeError> + Send + Sync,
+    >(
+        &self,
+        entity: &str,
+        func: F,
+    ) -> SimpleCommand {
         let hdmicec = self.clone();
-        return SimpleCommand::new(move |payload| {
-            return func(&hdmicec, payload);
+        let entity = entity.to_string();
+        return SimpleCommand::new(move |command| {
+            let result = func(&hdmicec, command);
+            hdmicec.report_command(&entity, &result);
+            return result;
         });
     }
 }
 
+/// Something we learned from the cec-client output.
+#[derive(Debug, Clone)]
+pub enum CecEvent {
+    /// the TV's power state, as "ON", "OFF" or "UNKNOWN".
+    Power(String),
+    /// the physical address of the active source, like "1.0.0.0".
+    ActiveSource(String),
+    /// any frame sent or received on the bus.
+    Traffic(CecFrame),
+    /// a command was handled, from MQTT or the API. 'error' is None if it worked.
+    Command {
+        entity: String,
+        error: Option<String>,
+    },
+}
+
+/// the logical address of the audio system, like a soundbar or AV receiver.
+pub const AUDIO_SYSTEM: u8 = 5;
+
+/// how many events can queue up for a slow listener before it starts missing them.
+const EVENT_CAPACITY: usize = 256;
+
+/// Something for the task that owns the cec-client process to do.
+enum ProcessRequest {
+    /// a line for cec-client, and when we were asked to send it.
+    Send(String, Instant),
+    Listen(Box<dyn FnMut(String) + Send>, Box<dyn FnOnce() + Send>),
+    Quit(Duration, oneshot::Sender<Result<(), std::io::Error>>),
+}
+
+/// A handle to the cec-client process. The process itself is owned by a separate task, so sending commands never blocks, and can be done from anywhere.
 pub struct HdmiCecProcess {
-    process: Mutex<CommandProcess>,
-    state: Mutex<Option<StateManager>>,
-    tv_state: Arc<Mutex<Option<String>>>,
+    requests: mpsc::UnboundedSender<ProcessRequest>,
+    state: Arc<Mutex<Option<StateManager>>>,
+    tv_state: watch::Sender<Option<String>>,
+    active_source: watch::Sender<Option<String>>,
+    events: broadcast::Sender<CecEvent>,
+    diagnostics: watch::Sender<CecDiagnostics>,
+    /// poked by detach_listeners(), to stop everything started with spawn_listener().
+    listeners: watch::Sender<()>,
 }
 
 impl HdmiCecProcess {
-    pub fn new() -> Self {
-        #[cfg(test)] // for testing, just use a dummy command, like cat!
-        let mut command = Command::new("cat");
-        #[cfg(not(test))] // in a real build, use cec-client
+    /// start up cec-client, or whatever is configured in its place. This needs to be called from inside the tokio runtime.
+    pub fn new(config: &CecConfig) -> Result<Self, BridgeError> {
+        let mut process = match (&config.replay, &config.simulator) {
+            (Some(replay), _) => {
+                CommandProcess::replay(&replay.file, replay.speed).map_err(|source| {
+                    BridgeError::Capture {
+                        path: replay.file.clone(),
+                        source,
+                    }
+                })?
+            }
+            (None, Some(simulator)) => {
+                CecSimulator::new(simulator, config.device_type.as_ref()).spawn()
+            }
+            (None, None) => CommandProcess::new(&mut HdmiCecProcess::cec_client_command(config))?,
+        };
+        metrics().cec_client_started();
+        if let Some(path) = &config.capture_file {
+            process
+                .capture_to(path)
+                .map_err(|source| BridgeError::Capture {
+                    path: path.clone(),
+                    source,
+                })?;
+        }
+
+        let (requests, mut receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(request) = receiver.recv().await {
+                match request {
+                    ProcessRequest::Send(input, sent) => match process.send(&input).await {
+                        Ok(()) => {
+                            let command = input.split_whitespace().next().unwrap_or("");
+                            metrics().command_sent(command, sent.elapsed());
+                        }
+                        Err(err) => {
+                            error!("could not send \"{}\" to cec-client: {err}", input.trim());
+                        }
+                    },
+                    ProcessRequest::Listen(func, on_close) => match process.with_output(func) {
+                        Ok(reader) => {
+                            tokio::spawn(async move {
+                                let _ = reader.await;
+                                on_close();
+                            });
+                        }
+                        Err(err) => error!("{err}"),
+                    },
+                    ProcessRequest::Quit(timeout, done) => {
+                        let _ = done.send(process.quit("q\n", timeout).await);
+                        return;
+                    }
+                }
+            }
+        });
+
+        return Ok(Self {
+            requests,
+            state: Arc::new(Mutex::new(None)),
+            tv_state: watch::Sender::new(None),
+            active_source: watch::Sender::new(None),
+            events: broadcast::Sender::new(EVENT_CAPACITY),
+            diagnostics: watch::Sender::new(CecDiagnostics::default()),
+            listeners: watch::Sender::new(()),
+        });
+    }
+
+    fn cec_client_command(config: &CecConfig) -> Command {
         let mut command = Command::new("cec-client");
 
         if !log_enabled!(log::Level::Trace) {
-            command.arg("-d").arg("1");
+            // errors (1), warnings (2), notices (4) and the traffic log (8) feed the diagnostics, and monitor mode. Debug (16) is far too chatty.
+            command.arg("-d").arg("15");
         }
+        command.args(config.as_args());
+        return command;
+    }
 
-        let process = CommandProcess::new(&mut command);
-        return Self {
-            process: Mutex::new(process),
-            state: Mutex::new(None),
-            tv_state: Arc::new(Mutex::new(None)),
-        };
+    /// call 'func' for everything we learn from the cec-client output, on a separate task. Listeners should be added before calling listen().
+    pub fn on_event<F: 'static + Fn(&CecEvent) + Send>(&self, func: F) {
+        let mut events = self.subscribe();
+        self.spawn_listener(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => func(&event),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("event listener is too slow, and missed {missed} events");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+
+    /// get a channel of everything we learn from the cec-client output.
+    pub fn subscribe(&self) -> broadcast::Receiver<CecEvent> {
+        return self.events.subscribe();
+    }
+
+    /// let event listeners know how a command went.
+    pub fn report_command(&self, entity: &str, result: &Result<(), BridgeError>) {
+        // it's fine if nobody is listening.
+        let _ = self.events.send(CecEvent::Command {
+            entity: entity.to_string(),
+            error: result.as_ref().err().map(|err| err.to_string()),
+        });
+    }
+
+    /// watch everything we know about the health of the bus.
+    pub fn diagnostics(&self) -> watch::Receiver<CecDiagnostics> {
+        return self.diagnostics.subscribe();
+    }
+
+    /// publish whatever 'value' picks out of the bus diagnostics to 'state', every time it changes.
+    pub fn on_diagnostic<F: 'static + Fn(&CecDiagnostics) -> Option<String> + Send>(
+        &self,
+        state: StateManager,
+        value: F,
+    ) {
+        let mut diagnostics = self.diagnostics.subscribe();
+        self.spawn_listener(async move {
+            let mut published = None;
+            loop {
+                let current = value(&diagnostics.borrow_and_update());
+                if current.is_some() && current != published {
+                    current.clone().map(|current| state.update_state(current));
+                    published = current;
+                }
+                if diagnostics.changed().await.is_err() {
+                    return;
+                }
+            }
+        });
     }
 
     pub fn attach_statemanager(&self, statemanager: StateManager) {
@@ -51,9 +229,45 @@ impl HdmiCecProcess {
             .replace(statemanager);
     }
 
+    /// run 'task' on a separate task, until it finishes or detach_listeners() is called. Anything an entity keeps running should be started this way, so the entities can be replaced on a reload.
+    pub fn spawn_listener<F: 'static + Future<Output = ()> + Send>(&self, task: F) {
+        let mut detached = self.listeners.subscribe();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = task => {},
+                _ = detached.changed() => {},
+            }
+        });
+    }
+
+    /// stop every listener, and forget the state manager, so a new set of entities can be connected without the old ones still publishing.
+    pub fn detach_listeners(&self) {
+        self.listeners.send_replace(());
+        self.state.lock().expect("could not get lock").take();
+    }
+
+    fn parse_events(line: &str) -> Vec<CecEvent> {
+        if let Some(power_state) = HdmiCecProcess::parse_power_state(line) {
+            return vec![CecEvent::Power(power_state)];
+        }
+        match CecFrame::parse_traffic(line) {
+            Some(frame) => {
+                let mut events = vec![];
+                frame.power_state().map(|power_state| {
+                    events.push(CecEvent::Power(power_state.to_string()));
+                });
+                frame.active_source().map(|source| {
+                    events.push(CecEvent::ActiveSource(source));
+                });
+                events.push(CecEvent::Traffic(frame));
+                return events;
+            }
+            None => return vec![],
+        }
+    }
+
     fn parse_power_state(line: &str) -> Option<String> {
-        if line.starts_with("power status:") {
-            let state_string = &line[14..];
+        if let Some(state_string) = line.strip_prefix("power status:").map(str::trim) {
             let mqtt_state = match state_string {
                 "on" => "ON",
                 "standby" => "OFF",
@@ -67,14 +281,15 @@ impl HdmiCecProcess {
         }
     }
 
-    pub fn update_state(&self, state: bool) {
-        let mqtt_state = if state { "ON" } else { "OFF" };
-
-        self.tv_state
-            .lock()
-            .expect("could not get lock")
-            .replace(mqtt_state.to_string());
-        self.state
+    /// publish a new power state for the TV, to the state manager and any event listeners.
+    fn publish_power_state(
+        state: &Mutex<Option<StateManager>>,
+        tv_state: &watch::Sender<Option<String>>,
+        mqtt_state: &str,
+    ) {
+        tv_state.send_replace(Some(mqtt_state.to_string()));
+        metrics().tv_power(mqtt_state);
+        state
             .lock()
             .expect("could not get lock")
             .as_ref()
@@ -83,81 +298,199 @@ impl HdmiCecProcess {
             });
     }
 
-    pub fn kill(&self) -> Result<(), std::io::Error> {
-        let mut process = self.process.lock().expect("could not lock process");
-        return process.kill();
+    /// the last power state we saw for the TV, as "ON", "OFF" or "UNKNOWN".
+    pub fn tv_state(&self) -> Option<String> {
+        return self.tv_state.borrow().clone();
+    }
+
+    /// the physical address of the last active source we saw, like "1.0.0.0".
+    pub fn active_source(&self) -> Option<String> {
+        return self.active_source.borrow().clone();
+    }
+
+    /// start out with the power state we retained last time, until the TV tells us otherwise.
+    pub fn restore_tv_state(&self, state: &str) {
+        self.tv_state.send_replace(Some(state.to_string()));
+    }
+
+    pub fn update_state(&self, state: bool) {
+        let mqtt_state = if state { "ON" } else { "OFF" };
+        HdmiCecProcess::publish_power_state(&self.state, &self.tv_state, mqtt_state);
+        // it's fine if nobody is listening.
+        let _ = self.events.send(CecEvent::Power(mqtt_state.to_string()));
     }
 
-    pub fn listen(&self) {
+    /// ask cec-client to quit, so it can let go of the bus cleanly. If it takes longer than 'timeout', it is killed.
+    pub async fn quit(&self, timeout: Duration) -> Result<(), std::io::Error> {
+        let (done, result) = oneshot::channel();
+        if self
+            .requests
+            .send(ProcessRequest::Quit(timeout, done))
+            .is_err()
+        {
+            // the process is already gone.
+            return Ok(());
+        }
+        return result.await.unwrap_or(Ok(()));
+    }
+
+    pub fn listen(&self) -> Result<(), BridgeError> {
         info!("listening to the cec-client process...");
-        let state = self
-            .state
-            .lock()
-            .expect("could not lock state")
-            .clone()
-            .expect("no state manager present while listening.");
+        let state = self.state.clone();
         let tv_state = self.tv_state.clone();
-        let mut process = self.process.lock().expect("could not lock process");
+        let active_source = self.active_source.clone();
+        let events = self.events.clone();
+        let diagnostics = self.diagnostics.clone();
+        let closed_diagnostics = self.diagnostics.clone();
 
-        process
-            .with_output(move |line| {
+        self.request(ProcessRequest::Listen(
+            Box::new(move |line| {
                 trace!("got line from stdout: {}", line);
-                if let Some(mqtt_state) = HdmiCecProcess::parse_power_state(&line) {
-                    tv_state
-                        .lock()
-                        .expect("could not get lock")
-                        .replace(mqtt_state.to_string());
-                    state.update_state(mqtt_state.to_string());
+                diagnostics.send_if_modified(|diagnostics| diagnostics.update(&line));
+                if transmit_failed(&line) {
+                    metrics().cec_transmit_failed();
                 }
-            })
-            .expect("could not start listening process");
+                for event in HdmiCecProcess::parse_events(&line) {
+                    match &event {
+                        CecEvent::Power(mqtt_state) => {
+                            HdmiCecProcess::publish_power_state(&state, &tv_state, mqtt_state);
+                        }
+                        CecEvent::Traffic(frame) => metrics().cec_frame(frame),
+                        CecEvent::ActiveSource(source) => {
+                            active_source.send_replace(Some(source.clone()));
+                        }
+                        CecEvent::Command { .. } => {}
+                    }
+                    let _ = events.send(event);
+                }
+            }),
+            Box::new(move || {
+                warn!("cec-client output closed");
+                closed_diagnostics.send_if_modified(|diagnostics| diagnostics.disconnected());
+            }),
+        ))
     }
 
-    pub fn volume_up(&self) {
-        let mut process = self.process.lock().expect("could not lock process");
-        process.send("volup\n").unwrap();
+    fn request(&self, request: ProcessRequest) -> Result<(), BridgeError> {
+        return self
+            .requests
+            .send(request)
+            .map_err(|_| BridgeError::ProcessStopped);
     }
 
-    pub fn set_tv(&self, state: bool) {
-        let mut process = self.process.lock().expect("could not lock process");
+    fn send(&self, command: &str) -> Result<(), BridgeError> {
+        return self.request(ProcessRequest::Send(command.to_string(), Instant::now()));
+    }
+
+    pub fn volume_up(&self) -> Result<(), BridgeError> {
+        return self.send("volup\n");
+    }
+
+    pub fn set_tv(&self, state: bool) -> Result<(), BridgeError> {
         if state {
-            process.send("on 0.0.0.0\n").unwrap();
+            self.send("on 0.0.0.0\n")?;
         } else {
-            process.send("standby 0.0.0.0\n").unwrap();
+            self.send("standby 0.0.0.0\n")?;
         }
         self.update_state(state);
+        return Ok(());
+    }
+
+    pub fn volume_down(&self) -> Result<(), BridgeError> {
+        return self.send("voldown\n");
+    }
+
+    pub fn mute(&self) -> Result<(), BridgeError> {
+        return self.send("mute\n");
     }
 
-    pub fn volume_down(&self) {
-        let mut process = self.process.lock().expect("could not lock process");
-        process.send("voldown\n").unwrap();
+    pub fn query_tv_state(&self) -> Result<(), BridgeError> {
+        return self.send("pow 0.0.0.0\n");
     }
 
-    pub fn mute(&self) {
-        let mut process = self.process.lock().expect("could not lock process");
-        process.send("mute\n").unwrap();
+    /// send a raw frame. The initiator is part of the frame, so it should usually be our own logical address.
+    pub fn transmit(&self, frame: &CecFrame) -> Result<(), BridgeError> {
+        return self.send(&format!("tx {}\n", frame.to_hex()));
     }
 
-    pub fn query_tv_state(&self) {
-        let mut process = self.process.lock().expect("could not lock process");
-        process.send("pow 0.0.0.0\n").unwrap();
+    /// press and release a key on the remote, for the device at 'destination'.
+    pub fn press_key(&self, destination: u8, key: UserControl) -> Result<(), BridgeError> {
+        let header = self.our_address() << 4 | (destination & 0x0f);
+        self.send(&format!("tx {header:02x}:44:{:02x}\n", key.code()))?;
+        return self.send(&format!("tx {header:02x}:45\n"));
     }
 
-    pub fn set_active_source(&self, source: usize) {
-        // not the best way to create the CEC frame.. but it works. taken from cec-o-matic at https://www.cec-o-matic.com/
-        let mut process = self.process.lock().expect("could not lock process");
-        process.send(&format!("tx 1F:82:{}0:00\n", source)).unwrap();
+    /// ask the audio system to set its volume, from 0 to 100, with <Set Audio Volume Level>. Only CEC 2.0 audio systems understand this.
+    pub fn set_volume(&self, level: u8) -> Result<(), BridgeError> {
+        let header = self.our_address() << 4 | AUDIO_SYSTEM;
+        return self.send(&format!("tx {header:02x}:73:{:02x}\n", level.min(100)));
+    }
+
+    /// ask the audio system to play the sound of the active source, or to stop, with <System Audio Mode Request>.
+    pub fn set_system_audio(&self, state: bool) -> Result<(), BridgeError> {
+        let header = self.our_address() << 4 | AUDIO_SYSTEM;
+        if !state {
+            return self.send(&format!("tx {header:02x}:70\n"));
+        }
+        // the TV's own address, if we haven't seen an active source yet.
+        let [high, low] = self
+            .active_source()
+            .as_deref()
+            .and_then(parse_physical_address)
+            .unwrap_or([0, 0]);
+        return self.send(&format!("tx {header:02x}:70:{high:02x}:{low:02x}\n"));
+    }
+
+    /// ask every other logical address who it is: its physical address, name and power state. The answers end up in the diagnostics, as they arrive.
+    pub fn poll_devices(&self) -> Result<(), BridgeError> {
+        let our_address = self.our_address();
+        for destination in (0..0xf).filter(|address| *address != our_address) {
+            let header = our_address << 4 | destination;
+            // <Give Physical Address>, <Give OSD Name> and <Give Device Power Status>
+            for opcode in [0x83, 0x46, 0x8f] {
+                self.send(&format!("tx {header:02x}:{opcode:02x}\n"))?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// the logical address we registered as. Until cec-client tells us, we assume the libcec default of "Recorder 1".
+    fn our_address(&self) -> u8 {
+        return self
+            .diagnostics
+            .borrow()
+            .logical_address
+            .as_deref()
+            .and_then(|address| address.rsplit_once('('))
+            .and_then(|(_, address)| address.split_once(')'))
+            .and_then(|(address, _)| address.parse().ok())
+            .unwrap_or(1);
+    }
+
+    /// broadcast <Active Source> for HDMI 'source', from whatever logical address we registered as.
+    pub fn set_active_source(&self, source: usize) -> Result<(), BridgeError> {
+        let header = self.our_address() << 4 | 0xf;
+        return self.send(&format!("tx {header:02x}:82:{source}0:00\n"));
     }
 }
 
-#[test]
-fn creating_hdmi_cec_process() {
-    HdmiCecProcess::new();
+/// a config for a simulated bus, so the tests don't need a real TV.
+#[cfg(test)]
+fn simulated_config() -> CecConfig {
+    return CecConfig {
+        simulator: Some(crate::config::SimulatorConfig::default()),
+        ..CecConfig::default()
+    };
 }
 
-#[test]
-fn hdmi_cec_process_functions() {
-    let cec = HdmiCecProcess::new();
+#[tokio::test]
+async fn creating_hdmi_cec_process() {
+    HdmiCecProcess::new(&simulated_config()).expect("could not start simulator");
+}
+
+#[tokio::test]
+async fn hdmi_cec_process_functions() {
+    let cec = HdmiCecProcess::new(&simulated_config()).expect("could not start simulator");
     assert!(cec.state.lock().expect("could not take lock").is_none());
 
     let statemanager = StateManager::faux();
@@ -165,6 +498,46 @@ fn hdmi_cec_process_functions() {
     assert!(cec.state.lock().expect("could not take lock").is_some());
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn detaching_listeners() {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let cec = HdmiCecProcess::new(&simulated_config()).expect("could not start simulator");
+    cec.attach_statemanager(StateManager::faux());
+    let (sender, receiver) = mpsc::channel();
+    cec.on_event(move |event| {
+        if let CecEvent::Command { entity, .. } = event {
+            sender.send(entity.clone()).expect("could not send entity");
+        }
+    });
+
+    cec.report_command("mute", &Ok(()));
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_secs(1)),
+        Ok("mute".to_string())
+    );
+
+    // the listener is dropped along with its task, which hangs up the channel.
+    cec.detach_listeners();
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_secs(1)),
+        Err(mpsc::RecvTimeoutError::Disconnected)
+    );
+    assert!(cec.state.lock().expect("could not take lock").is_none());
+}
+
+#[tokio::test]
+async fn commanding_a_stopped_process() {
+    use std::time::Duration;
+
+    let cec = HdmiCecProcess::new(&simulated_config()).expect("could not start simulator");
+    cec.quit(Duration::from_secs(1))
+        .await
+        .expect("could not quit simulator");
+    assert!(matches!(cec.mute(), Err(BridgeError::ProcessStopped)));
+}
+
 #[test]
 fn parsing_power_line() {
     assert_eq!(
@@ -179,6 +552,190 @@ fn parsing_power_line() {
         HdmiCecProcess::parse_power_state("power status: idk"),
         Some("UNKNOWN".to_string())
     );
+    // cec-client can cut a line short, which shouldn't take the bridge down.
+    assert_eq!(
+        HdmiCecProcess::parse_power_state("power status:"),
+        Some("UNKNOWN".to_string())
+    );
 
     assert_eq!(HdmiCecProcess::parse_power_state("random junk"), None);
 }
+
+#[test]
+fn parsing_traffic_events() {
+    let events = HdmiCecProcess::parse_events("TRAFFIC: [    5312]\t>> 4f:82:30:00");
+    assert!(matches!(&events[..], [
+        CecEvent::ActiveSource(source),
+        CecEvent::Traffic(_)
+    ] if source == "3.0.0.0"));
+
+    let events = HdmiCecProcess::parse_events("power status: standby");
+    assert!(matches!(&events[..], [CecEvent::Power(state)] if state == "OFF"));
+
+    assert!(HdmiCecProcess::parse_events("random junk").is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn replaying_a_capture() {
+    use crate::config::ReplayConfig;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let config = CecConfig {
+        replay: Some(ReplayConfig {
+            file: concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/captures/power_cycle.cap"
+            )
+            .to_string(),
+            speed: f64::INFINITY,
+        }),
+        ..CecConfig::default()
+    };
+    let cec = HdmiCecProcess::new(&config).expect("could not start replay");
+
+    let mut statemanager = StateManager::faux();
+    faux::when!(statemanager.update_state).then(|_state| ());
+    cec.attach_statemanager(statemanager);
+
+    let (sender, receiver) = mpsc::channel();
+    cec.on_event(move |event| {
+        if let CecEvent::Power(state) = event {
+            sender.send(state.clone()).expect("could not send state");
+        }
+    });
+    cec.listen().expect("could not listen");
+
+    let states: Vec<String> = (0..5)
+        .map(|_| {
+            receiver
+                .recv_timeout(Duration::from_secs(1))
+                .expect("no power state")
+        })
+        .collect();
+    assert_eq!(states, vec!["OFF", "ON", "ON", "ON", "OFF"]);
+}
+
+#[tokio::test]
+async fn replaying_a_missing_capture() {
+    use crate::config::ReplayConfig;
+
+    let config = CecConfig {
+        replay: Some(ReplayConfig {
+            file: "/nonexistent/power_cycle.cap".to_string(),
+            speed: f64::INFINITY,
+        }),
+        ..CecConfig::default()
+    };
+    assert!(matches!(
+        HdmiCecProcess::new(&config),
+        Err(BridgeError::Capture { path, .. }) if path == "/nonexistent/power_cycle.cap"
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn watching_bus_diagnostics() {
+    use std::time::Duration;
+
+    let cec = HdmiCecProcess::new(&simulated_config()).expect("could not start simulator");
+    let mut diagnostics = cec.diagnostics.subscribe();
+    cec.listen().expect("could not listen");
+    cec.set_tv(true).expect("could not switch tv");
+
+    let seen = tokio::time::timeout(
+        Duration::from_secs(1),
+        diagnostics.wait_for(|diagnostics| diagnostics.frames_sent > 0),
+    )
+    .await
+    .expect("timed out waiting for diagnostics")
+    .expect("diagnostics closed")
+    .clone();
+    assert_eq!(seen.connected, Some(true));
+    assert_eq!(seen.logical_address.as_deref(), Some("Recorder 1 (1)"));
+    assert!(seen.libcec_version.is_some());
+
+    cec.quit(Duration::from_secs(1))
+        .await
+        .expect("could not quit simulator");
+    tokio::time::timeout(
+        Duration::from_secs(1),
+        diagnostics.wait_for(|diagnostics| diagnostics.connected == Some(false)),
+    )
+    .await
+    .expect("cec-client never disconnected")
+    .expect("diagnostics closed");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn switching_sources_as_a_playback_device() {
+    use crate::cec_frame::Direction;
+    use crate::config::CecDeviceType;
+    use std::time::Duration;
+
+    let cec = HdmiCecProcess::new(&CecConfig {
+        device_type: Some(CecDeviceType::Playback),
+        ..simulated_config()
+    })
+    .expect("could not start simulator");
+    let mut diagnostics = cec.diagnostics();
+    let mut events = cec.subscribe();
+    cec.listen().expect("could not listen");
+    tokio::time::timeout(
+        Duration::from_secs(1),
+        diagnostics.wait_for(|diagnostics| diagnostics.logical_address.is_some()),
+    )
+    .await
+    .expect("cec-client never registered")
+    .expect("diagnostics closed");
+
+    cec.set_active_source(2).expect("could not switch source");
+    let frame = tokio::time::timeout(Duration::from_secs(1), async {
+        loop {
+            if let Ok(CecEvent::Traffic(frame)) = events.recv().await {
+                if frame.direction == Direction::Sent {
+                    return frame;
+                }
+            }
+        }
+    })
+    .await
+    .expect("no frame sent");
+    assert_eq!(frame.to_hex(), "4f:82:20:00");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn controlling_a_simulated_tv() {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let cec = HdmiCecProcess::new(&simulated_config()).expect("could not start simulator");
+
+    let (sender, receiver) = mpsc::channel();
+    let mut statemanager = StateManager::faux();
+    faux::when!(statemanager.update_state).then(move |state| {
+        sender.send(state).expect("could not send state");
+    });
+    cec.attach_statemanager(statemanager);
+    cec.listen().expect("could not listen");
+
+    let next_states = |count: usize| {
+        (0..count)
+            .map(|_| {
+                receiver
+                    .recv_timeout(Duration::from_secs(1))
+                    .expect("no power state")
+            })
+            .collect::<Vec<String>>()
+    };
+
+    // the simulator reports the power state in the traffic log, and in the "power status" line.
+    cec.query_tv_state().expect("could not query tv");
+    assert_eq!(next_states(2), vec!["OFF", "OFF"]);
+
+    // the switch updates optimistically, and then we see the "Image View On" go out.
+    cec.set_tv(true).expect("could not switch tv");
+    assert_eq!(next_states(2), vec!["ON", "ON"]);
+
+    cec.query_tv_state().expect("could not query tv");
+    assert_eq!(next_states(2), vec!["ON", "ON"]);
+}
@@ -0,0 +1,87 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::cec_diagnostics::CecDiagnostics;
+
+/// Whether the bridge is actually working, as opposed to just running. This watches the MQTT connection, and the cec-client diagnostics.
+#[derive(Clone)]
+pub struct Health {
+    mqtt_connected: watch::Receiver<bool>,
+    diagnostics: watch::Receiver<CecDiagnostics>,
+    /// how old the last power report from the TV can be. None if we never poll the TV, like in monitor mode.
+    max_poll_age: Option<Duration>,
+}
+
+/// The current health, as served on the /health endpoint.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub mqtt_connected: bool,
+    pub cec_client_alive: bool,
+    /// seconds since the TV last told us its power state.
+    pub power_poll_age: Option<f64>,
+}
+
+impl Health {
+    pub fn new(
+        mqtt_connected: watch::Receiver<bool>,
+        diagnostics: watch::Receiver<CecDiagnostics>,
+        max_poll_age: Option<Duration>,
+    ) -> Self {
+        return Self {
+            mqtt_connected,
+            diagnostics,
+            max_poll_age,
+        };
+    }
+
+    pub fn report(&self) -> HealthReport {
+        let mqtt_connected = *self.mqtt_connected.borrow();
+        let diagnostics = self.diagnostics.borrow();
+        let cec_client_alive = diagnostics.connected == Some(true);
+        let power_poll_age = diagnostics
+            .last_power_report
+            .map(|reported| Instant::now().duration_since(reported));
+        // without a single report yet, we are still starting up, which counts as degraded too.
+        let polling = self.max_poll_age.is_none_or(|max_poll_age| {
+            power_poll_age.is_some_and(|power_poll_age| power_poll_age <= max_poll_age)
+        });
+
+        return HealthReport {
+            healthy: mqtt_connected && cec_client_alive && polling,
+            mqtt_connected,
+            cec_client_alive,
+            power_poll_age: power_poll_age.map(|age| age.as_secs_f64()),
+        };
+    }
+}
+
+#[test]
+fn reporting_health() {
+    let (mqtt, mqtt_connected) = watch::channel(false);
+    let (diagnostics, cec_diagnostics) = watch::channel(CecDiagnostics::default());
+    let health = Health::new(
+        mqtt_connected,
+        cec_diagnostics,
+        Some(Duration::from_secs(60)),
+    );
+    assert!(!health.report().healthy);
+
+    mqtt.send_replace(true);
+    diagnostics.send_modify(|diagnostics| {
+        diagnostics.update("waiting for input");
+    });
+    let report = health.report();
+    assert!(report.mqtt_connected && report.cec_client_alive);
+    assert!(!report.healthy, "the TV never reported its power state");
+
+    diagnostics.send_modify(|diagnostics| {
+        diagnostics.update("power status: on");
+    });
+    assert!(health.report().healthy);
+
+    mqtt.send_replace(false);
+    assert!(!health.report().healthy);
+}
@@ -0,0 +1,186 @@
+use anyhow::{anyhow, Context, Error};
+use axum::{
+    extract::State,
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use log::{error, info};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::config::{Config, HttpConfig};
+use crate::health::Health;
+use crate::metrics::metrics;
+
+/// Start serving 'router', on a separate task. Binding the port happens right away, so a port that is already taken is reported here.
+pub async fn start(config: &HttpConfig, router: Router) -> Result<JoinHandle<()>, Error> {
+    let listener = TcpListener::bind(&config.listen)
+        .await
+        .with_context(|| format!("could not listen for http on {}", config.listen))?;
+    info!("listening for http on {}", config.listen);
+    return Ok(tokio::spawn(serve(listener, router)));
+}
+
+pub async fn serve(listener: TcpListener, router: Router) {
+    if let Err(err) = axum::serve(listener, router).await {
+        error!("http listener failed: {err}");
+    }
+}
+
+/// The routes that are always there: metrics and health. More can be merged in, like the API.
+pub fn router(health: Health) -> Router {
+    return Router::new()
+        .route("/metrics", get(prometheus_metrics))
+        .route("/health", get(health_report))
+        .with_state(health);
+}
+
+async fn prometheus_metrics() -> impl IntoResponse {
+    return (
+        [(CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        metrics().render(),
+    );
+}
+
+/// 200 when everything is working, and 503 when anything is degraded, with the details either way.
+async fn health_report(State(health): State<Health>) -> impl IntoResponse {
+    let report = health.report();
+    let status = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    return (status, Json(report));
+}
+
+/// Ask a running bridge how it is doing, for docker's HEALTHCHECK. This fails if the bridge is degraded, or not there at all.
+pub async fn healthcheck(config: &Config) -> Result<(), Error> {
+    let http = match &config.http {
+        Some(http) => http,
+        None => {
+            // nothing to ask, so don't mark every container without an [http] section as unhealthy.
+            info!("the http listener is disabled, so there is no health to check");
+            return Ok(());
+        }
+    };
+    let address = local_address(&http.listen).await?;
+    let (status, body) = request(&address.to_string(), "GET", "/health", "").await?;
+    println!("{body}");
+    if status != 200 {
+        return Err(anyhow!("unhealthy: {body}"));
+    }
+    return Ok(());
+}
+
+/// where to reach a bridge listening on 'listen' from the same machine. 'listen' can be a hostname, like "localhost:9464". We can't connect to the wildcard address, but the bridge is listening on localhost too.
+async fn local_address(listen: &str) -> Result<SocketAddr, Error> {
+    let mut address = lookup_host(listen)
+        .await
+        .with_context(|| format!("\"{listen}\" is not an address and port"))?
+        .next()
+        .ok_or_else(|| anyhow!("\"{listen}\" didn't resolve to any address"))?;
+    if address.ip().is_unspecified() {
+        address.set_ip(match address.ip() {
+            IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    return Ok(address);
+}
+
+/// a bare bones HTTP client, which is all healthcheck() needs. Returns the status code and body.
+pub async fn request(
+    address: &str,
+    method: &str,
+    path: &str,
+    body: &str,
+) -> Result<(u16, String), Error> {
+    let mut stream = TcpStream::connect(address)
+        .await
+        .with_context(|| format!("could not connect to {address}"))?;
+    let length = body.len();
+    stream
+        .write_all(
+            format!("{method} {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {length}\r\n\r\n{body}")
+                .as_bytes(),
+        )
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow!("invalid http response from {address}"))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| anyhow!("invalid http status from {address}"))?;
+    return Ok((status, body.to_string()));
+}
+
+#[tokio::test]
+async fn serving_metrics_and_health() {
+    use crate::cec_diagnostics::CecDiagnostics;
+    use tokio::sync::watch;
+
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("could not bind");
+    let address = listener.local_addr().expect("no local address").to_string();
+    let (mqtt, mqtt_connected) = watch::channel(false);
+    let (_diagnostics, cec_diagnostics) = watch::channel(CecDiagnostics {
+        connected: Some(true),
+        ..CecDiagnostics::default()
+    });
+    tokio::spawn(serve(
+        listener,
+        router(Health::new(mqtt_connected, cec_diagnostics, None)),
+    ));
+
+    metrics().cec_client_started();
+    let (status, body) = request(&address, "GET", "/metrics", "")
+        .await
+        .expect("could not get metrics");
+    assert_eq!(status, 200);
+    assert!(body.contains("hdmicec2mqtt_cec_client_starts_total"));
+
+    let (status, body) = request(&address, "GET", "/health", "")
+        .await
+        .expect("could not get health");
+    assert_eq!(status, 503);
+    assert!(body.contains(r#""mqtt_connected":false"#));
+
+    mqtt.send_replace(true);
+    let (status, _body) = request(&address, "GET", "/health", "")
+        .await
+        .expect("could not get health");
+    assert_eq!(status, 200);
+}
+
+#[tokio::test]
+async fn reaching_the_bridge_locally() {
+    let address = |listen| async move {
+        local_address(listen)
+            .await
+            .map(|address| address.to_string())
+    };
+    assert_eq!(address("0.0.0.0:9464").await.unwrap(), "127.0.0.1:9464");
+    assert_eq!(address("[::]:9464").await.unwrap(), "[::1]:9464");
+    assert_eq!(
+        address("192.168.1.5:9464").await.unwrap(),
+        "192.168.1.5:9464"
+    );
+    assert!(address("9464").await.is_err());
+    let localhost = local_address("localhost:9464")
+        .await
+        .expect("localhost didn't resolve");
+    assert!(localhost.ip().is_loopback());
+    assert_eq!(localhost.port(), 9464);
+}
@@ -0,0 +1,336 @@
+//! Macros from the config, which chain CEC commands together, like turning the TV on, waiting for it, and then switching sources. Running every step from here keeps them in order, which separate buttons in homeassistant can't.
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{error, info};
+use tokio::sync::broadcast;
+
+use crate::cec_frame::{CecFrame, Direction};
+use crate::config::{MacroConfig, MacroStep, PowerState, WaitCondition};
+use crate::error::BridgeError;
+use crate::hdmicec_entity::{CecEvent, HdmiCecProcess, AUDIO_SYSTEM};
+use crate::service::StateManager;
+
+/// how often to poll the TV, while waiting for it to turn on or off.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// how long to wait between volume presses, so the audio system doesn't miss any.
+const PRESS_INTERVAL: Duration = Duration::from_millis(250);
+/// how long the audio system has to report its volume, after we set it.
+const AUDIO_STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// clears the running flag when a macro stops, even if its task was aborted by a reload.
+struct Running<'a>(&'a AtomicBool);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Runs the steps of one macro against the bus, and reports how it is going to a status sensor.
+pub struct MacroRunner {
+    hdmicec: Arc<HdmiCecProcess>,
+    config: MacroConfig,
+    status: Mutex<Option<StateManager>>,
+    running: AtomicBool,
+}
+
+impl MacroRunner {
+    pub fn new(hdmicec: Arc<HdmiCecProcess>, config: MacroConfig) -> Arc<Self> {
+        return Arc::new(Self {
+            hdmicec,
+            config,
+            status: Mutex::new(None),
+            running: AtomicBool::new(false),
+        });
+    }
+
+    /// publish progress to 'status' from now on, starting out as "idle".
+    pub fn attach_status(&self, status: StateManager) {
+        status.update_state("idle".to_string());
+        self.status
+            .lock()
+            .expect("could not get lock")
+            .replace(status);
+    }
+
+    /// run the steps on a separate task. A macro only runs once at a time, so pressing it again while it is running is an error.
+    pub fn start(self: &Arc<Self>) -> Result<(), BridgeError> {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err(BridgeError::MacroRunning {
+                name: self.config.id.clone(),
+            });
+        }
+        let runner = self.clone();
+        // a reload stops the macro along with everything else the old entities started.
+        self.hdmicec.spawn_listener(async move {
+            info!("running macro \"{}\"", runner.config.id);
+            let result = {
+                let _running = Running(&runner.running);
+                runner.run().await
+            };
+            match &result {
+                Ok(()) => runner.publish_status("done".to_string()),
+                Err(err) => {
+                    error!("{err}");
+                    runner.publish_status(format!("failed: {err}"));
+                }
+            }
+            runner
+                .hdmicec
+                .report_command(&format!("macro_{}", runner.config.id), &result);
+        });
+        return Ok(());
+    }
+
+    async fn run(&self) -> Result<(), BridgeError> {
+        let total = self.config.steps.len();
+        for (index, step) in self.config.steps.iter().enumerate() {
+            self.publish_status(format!("step {}/{total}: {step}", index + 1));
+            self.run_step(step)
+                .await
+                .map_err(|reason| BridgeError::MacroFailed {
+                    name: self.config.id.clone(),
+                    step: index + 1,
+                    reason,
+                })?;
+        }
+        return Ok(());
+    }
+
+    async fn run_step(&self, step: &MacroStep) -> Result<(), String> {
+        let hdmicec = &self.hdmicec;
+        let sent = match step {
+            MacroStep::Power(state) => hdmicec.set_tv(*state == PowerState::On),
+            MacroStep::WaitFor(condition) => return self.wait_for(condition).await,
+            MacroStep::Source(source) => hdmicec.set_active_source(*source),
+            MacroStep::SystemAudio(state) => hdmicec.set_system_audio(*state == PowerState::On),
+            MacroStep::Volume(level) => return self.set_volume(*level).await,
+            MacroStep::VolumeUp(presses) => self.press(*presses, || hdmicec.volume_up()).await,
+            MacroStep::VolumeDown(presses) => self.press(*presses, || hdmicec.volume_down()).await,
+            MacroStep::Mute => hdmicec.mute(),
+            MacroStep::Send(frame) => {
+                // validate() already checked this, but a config doesn't have to go through it.
+                let frame = CecFrame::parse(frame, Direction::Sent)
+                    .ok_or_else(|| format!("\"{frame}\" is not a CEC frame"))?;
+                hdmicec.transmit(&frame)
+            }
+            MacroStep::Delay(seconds) => {
+                tokio::time::sleep(seconds_to_wait(*seconds)?).await;
+                Ok(())
+            }
+        };
+        return sent.map_err(|err| err.to_string());
+    }
+
+    /// set the volume, and check that the audio system reports it back with <Report Audio Status>.
+    async fn set_volume(&self, level: u8) -> Result<(), String> {
+        let mut events = self.hdmicec.subscribe();
+        self.hdmicec
+            .set_volume(level)
+            .map_err(|err| err.to_string())?;
+        let reported = tokio::time::timeout(AUDIO_STATUS_TIMEOUT, async {
+            loop {
+                match events.recv().await {
+                    Ok(CecEvent::Traffic(frame))
+                        if frame.direction == Direction::Received
+                            && frame.initiator == AUDIO_SYSTEM
+                            && frame.opcode == Some(0x7a) =>
+                    {
+                        // the top bit is the mute flag, and the rest is the volume.
+                        if let Some(status) = frame.parameters.first() {
+                            return Ok(status & 0x7f);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err(BridgeError::ProcessStopped.to_string());
+                    }
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| {
+            Err(format!(
+                "the audio system did not report its volume within {} seconds",
+                AUDIO_STATUS_TIMEOUT.as_secs()
+            ))
+        })?;
+        let wanted = level.min(100);
+        if reported != wanted {
+            return Err(format!(
+                "the audio system reported volume {reported} instead of {wanted}"
+            ));
+        }
+        return Ok(());
+    }
+
+    /// call 'press' this many times, with a little time between each.
+    async fn press<F: Fn() -> Result<(), BridgeError>>(
+        &self,
+        presses: u8,
+        press: F,
+    ) -> Result<(), BridgeError> {
+        for index in 0..presses {
+            if index > 0 {
+                tokio::time::sleep(PRESS_INTERVAL).await;
+            }
+            press()?;
+        }
+        return Ok(());
+    }
+
+    /// poll the TV until it reports the power state we are waiting for. What we assumed after switching it doesn't count, only what the TV says.
+    async fn wait_for(&self, condition: &WaitCondition) -> Result<(), String> {
+        let wanted = condition.power.to_string();
+        let mut events = self.hdmicec.subscribe();
+        let mut poll = tokio::time::interval(POLL_INTERVAL);
+        let waited = tokio::time::timeout(seconds_to_wait(condition.timeout)?, async {
+            loop {
+                tokio::select! {
+                    _ = poll.tick() => {
+                        self.hdmicec.query_tv_state().map_err(|err| err.to_string())?;
+                    },
+                    event = events.recv() => match event {
+                        Ok(CecEvent::Power(state)) if state == wanted => return Ok(()),
+                        Err(broadcast::error::RecvError::Closed) => {
+                            return Err(BridgeError::ProcessStopped.to_string());
+                        }
+                        _ => {}
+                    },
+                }
+            }
+        })
+        .await;
+        return waited.unwrap_or_else(|_| {
+            Err(format!(
+                "the TV did not report {wanted} within {} seconds",
+                condition.timeout
+            ))
+        });
+    }
+
+    fn publish_status(&self, status: String) {
+        self.status
+            .lock()
+            .expect("could not get lock")
+            .as_ref()
+            .map(|state| state.update_state(status));
+    }
+}
+
+/// 'seconds' as a Duration. validate() already checked these, but a config doesn't have to go through it.
+fn seconds_to_wait(seconds: f64) -> Result<Duration, String> {
+    return Duration::try_from_secs_f64(seconds)
+        .map_err(|_| format!("{seconds} is not a number of seconds to wait"));
+}
+
+/// what a step does, for the status sensor, like "switch to HDMI 2".
+impl Display for MacroStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            MacroStep::Power(PowerState::On) => write!(f, "turn the TV on"),
+            MacroStep::Power(PowerState::Off) => write!(f, "turn the TV off"),
+            MacroStep::WaitFor(condition) => {
+                write!(f, "wait for the TV to report {}", condition.power)
+            }
+            MacroStep::Source(source) => write!(f, "switch to HDMI {source}"),
+            MacroStep::SystemAudio(PowerState::On) => write!(f, "turn system audio on"),
+            MacroStep::SystemAudio(PowerState::Off) => write!(f, "turn system audio off"),
+            MacroStep::Volume(level) => write!(f, "set the volume to {level}"),
+            MacroStep::VolumeUp(presses) => write!(f, "press volume up {presses} times"),
+            MacroStep::VolumeDown(presses) => write!(f, "press volume down {presses} times"),
+            MacroStep::Mute => write!(f, "mute"),
+            MacroStep::Send(frame) => write!(f, "send {frame}"),
+            MacroStep::Delay(seconds) => write!(f, "wait {seconds} seconds"),
+        };
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn running_macros() {
+    use crate::config::{CecConfig, SimulatorConfig};
+    use std::sync::mpsc;
+
+    let hdmicec = Arc::new(
+        HdmiCecProcess::new(&CecConfig {
+            simulator: Some(SimulatorConfig::default()),
+            ..CecConfig::default()
+        })
+        .expect("could not start simulator"),
+    );
+    hdmicec.listen().expect("could not listen");
+    let config: MacroConfig = toml::from_str(
+        r#"
+        id = "movie_night"
+        steps = [
+            { power = "on" },
+            { wait_for = { power = "on", timeout = 5 } },
+            { source = 2 },
+            { system_audio = "on" },
+            { volume = 25 },
+            "mute",
+            { wait_for = { power = "off", timeout = 0.5 } },
+            { delay = 10 },
+        ]
+        "#,
+    )
+    .expect("invalid macro");
+    let runner = MacroRunner::new(hdmicec.clone(), config);
+
+    let (sender, statuses) = mpsc::channel();
+    let mut status = StateManager::faux();
+    faux::when!(status.update_state).then(move |state| {
+        let _ = sender.send(state);
+    });
+    runner.attach_status(status);
+    let mut events = hdmicec.subscribe();
+    runner.start().expect("could not start macro");
+    assert!(matches!(
+        runner.start(),
+        Err(BridgeError::MacroRunning { .. })
+    ));
+
+    let statuses: Vec<String> = (0..9)
+        .map(|_| {
+            statuses
+                .recv_timeout(Duration::from_secs(5))
+                .expect("no status")
+        })
+        .collect();
+    assert_eq!(
+        statuses,
+        vec![
+            "idle",
+            "step 1/8: turn the TV on",
+            "step 2/8: wait for the TV to report ON",
+            "step 3/8: switch to HDMI 2",
+            "step 4/8: turn system audio on",
+            "step 5/8: set the volume to 25",
+            "step 6/8: mute",
+            "step 7/8: wait for the TV to report OFF",
+            "failed: macro \"movie_night\" failed at step 7: the TV did not report OFF within 0.5 seconds",
+        ]
+    );
+    assert!(runner.start().is_ok());
+
+    // the soundbar went along with all of it.
+    let mut replies = vec![];
+    while let Ok(event) = events.try_recv() {
+        if let CecEvent::Traffic(frame) = event {
+            if frame.initiator == 5 && frame.direction == Direction::Received {
+                replies.push(frame.to_hex());
+            }
+        }
+    }
+    assert!(replies.contains(&"5f:72:01".to_string()));
+    assert!(replies.contains(&"51:7a:19".to_string()));
+    assert!(replies.contains(&"51:7a:99".to_string()));
+
+    // a config that skipped validate() fails the step instead of panicking.
+    assert!(seconds_to_wait(-1.0).is_err());
+    assert!(seconds_to_wait(f64::INFINITY).is_err());
+    assert_eq!(seconds_to_wait(1.5), Ok(Duration::from_millis(1500)));
+}
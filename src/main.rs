@@ -1,132 +1,105 @@
-use anyhow::Error;
-use ha_entity::{Device, DeviceClass, EntityClass};
-use log::{debug, info};
-use std::{env, fs, sync::Arc, thread, time::Duration};
+// the codebase prefers explicit returns, and `Option::map` for side effects.
+#![allow(
+    clippy::needless_return,
+    clippy::option_map_unit_fn,
+    clippy::unused_unit
+)]
+
+use std::process::ExitCode;
 
+use anyhow::Error;
+use args::{Args, Command};
+use clap::Parser;
+use log::info;
+
+mod api;
+mod args;
+mod bridge;
+mod capture;
+mod cec_diagnostics;
+mod cec_frame;
+mod cec_simulator;
+mod cli;
+mod command;
 mod config;
+mod device_class;
+mod entity_store;
+mod error;
 mod ha_entity;
 mod hdmicec_entity;
+mod health;
+mod http;
+mod macros;
+mod metrics;
 mod payloads;
 mod process;
+mod reload;
 mod service;
+#[cfg(test)]
+mod test_harness;
+mod websocket;
 
-const CONFIG_FILE: &'static str = "config.toml";
-
-fn main() -> Result<(), Error> {
+#[tokio::main]
+async fn main() -> ExitCode {
     use env_logger::Env;
-    use hdmicec_entity::{ClonableHdmiCecProcess, HdmiCecProcess};
-    use service::HaBroker;
+
+    let args = Args::parse();
 
     // default to sending info or above messages.
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let mut logger = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
+    args.log_level.as_ref().map(|level| {
+        logger.parse_filters(level);
+    });
+    logger.init();
 
     info!("Starting up...");
 
-    // load in the config file
-    let args: Vec<String> = env::args().collect();
-    let config_file_path: &str = args.get(1).map(|s| s.as_str()).unwrap_or(CONFIG_FILE);
-    info!("Reading config file at {config_file_path}");
-    let config_file_contents: String = match fs::read_to_string(config_file_path) {
-        Ok(content) => content,
+    // just the message and its causes, without a backtrace, like clap does for invalid arguments.
+    return match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
-            panic!("Error reading config file \"{config_file_path}\" contents: {err}");
+            eprintln!("error: {err:#}");
+            ExitCode::FAILURE
         }
     };
+}
 
-    let config: config::Config = match toml::from_str(&config_file_contents) {
-        Ok(config) => config,
-        Err(err) => {
-            panic!("Error parsing config file: {err}");
-        }
+async fn run(args: Args) -> Result<(), Error> {
+    args.check_dry_run()?;
+    let config = args.load_config()?;
+    if args.check_config {
+        println!("{} is valid", args.config_path());
+        return Ok(());
+    }
+    if args.print_discovery {
+        return bridge::print_discovery(config).await;
+    }
+
+    return match args.command {
+        // removes everything we ever added to homeassistant, instead of running the bridge.
+        Some(Command::Purge) => bridge::purge(config).await,
+        // asks a running bridge how it is doing.
+        Some(Command::Healthcheck) => http::healthcheck(&config).await,
+        // the rest, like "power on", work on the CEC bus directly, without MQTT.
+        Some(Command::Cec(command)) => cli::run(&config.cec, command, shutdown_signal()).await,
+        // reloads are read with the same overrides as the config we start with.
+        None => bridge::run(config, shutdown_signal(), reload::watch(args)).await,
     };
+}
 
-    //start up the cec-client process. We will share this in a few different
-    // threads, so we'll wrap it in a Arc so we can clone it.
-    let hdmicec = Arc::new(HdmiCecProcess::new());
-    let switch_hdmicec = hdmicec.clone(); // clone so we can move into a closure later.
-
-    // Every entity should be part of a "Device" for homeassistant.
-    let device = Device::from_config(&config);
-
-    // Setup a "switch" device for the TV's power state.
-    let switch = device
-        .entity("tv", EntityClass::Switch, DeviceClass::Switch)
-        .with_state(move |state| {
-            switch_hdmicec.attach_statemanager(state.clone());
-
-            // make another clone for the next closure...
-            let switch_hdmicec = switch_hdmicec.clone();
-            thread::spawn(move || loop {
-                debug!("querying TV...");
-                switch_hdmicec.query_tv_state();
-                thread::sleep(Duration::from_secs(10));
-            });
-        })
-        .with_commands(hdmicec.command(move |hdmicec, payload| {
-            let status = match payload {
-                "ON" => true,
-                _ => false,
-            };
-            info!("Switching TV {}", if status { "on" } else { "off" });
-            hdmicec.set_tv(status);
-        }));
-
-    // Setup a simple button for turning the volume up
-    let vol_up = device
-        .entity("volumeup", EntityClass::Button, DeviceClass::None)
-        .with_commands(hdmicec.command(|hdmicec, _payload| {
-            info!("Volume Up");
-            hdmicec.volume_up();
-        }));
-
-    // Setup a simple button for turning the volume down
-    let vol_down = device
-        .entity("volumedown", EntityClass::Button, DeviceClass::None)
-        .with_commands(hdmicec.command(|hdmicec, _payload| {
-            info!("Volume Down");
-            hdmicec.volume_down();
-        }));
-
-    // Setup a simple button for muting
-    let mute = device
-        .entity("mute", EntityClass::Button, DeviceClass::None)
-        .with_commands(hdmicec.command(|hdmicec, _payload| {
-            info!("Mute");
-            hdmicec.mute();
-        }));
-
-    // Setup a simple button for 4 sources. It's unclear to me if CEC even
-    //supports more than 4 input sources. TODO maybe use a Select entity? that
-    // will require reading the state though, unless we want to use optimistic mode.
-    let sources = (1..5).map(|i| {
-        return device
-            .entity(
-                &format!("Source{}", i),
-                EntityClass::Button,
-                DeviceClass::None,
-            )
-            .with_commands(hdmicec.command(move |hdmicec, _payload| {
-                info!("Source {}", i);
-                hdmicec.set_active_source(i as usize);
-            }));
-    });
-
-    // start up the mqtt client, and attach all our entities.
-    // then, start listening for mqtt messages, and output from
-    // cec-client.
-    // (note that homeassistant.listen() does not spawn a new thread
-    // it never returns, and needs to be last.)
-    let mut homeassistant = HaBroker::from_config(config);
-    homeassistant.add_entity(switch);
-    homeassistant.add_entity(vol_up);
-    homeassistant.add_entity(vol_down);
-    homeassistant.add_entity(mute);
-    sources.for_each(|source| {
-        homeassistant.add_entity(source);
-    });
-    hdmicec.listen();
-    let err = homeassistant.listen();
-
-    hdmicec.kill().expect("could not kill cec-client process");
-    return err;
+/// wait for SIGINT (ctrl-c), or SIGTERM, which is what `docker stop` sends.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("could not listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
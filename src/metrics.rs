@@ -0,0 +1,158 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use prometheus::{
+    histogram_opts, opts, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, Registry,
+    TextEncoder,
+};
+
+use crate::cec_frame::{CecFrame, Direction};
+
+/// Counters and gauges for prometheus. There is only one of these, shared by everything that reports to it, see metrics().
+pub struct Metrics {
+    registry: Registry,
+    mqtt_messages: IntCounterVec,
+    cec_frames: IntCounterVec,
+    cec_transmits: IntCounterVec,
+    command_duration: HistogramVec,
+    cec_client_starts: IntCounter,
+    tv_power: IntGauge,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// the metrics for this process.
+pub fn metrics() -> &'static Metrics {
+    return &METRICS;
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("hdmicec2mqtt".to_string()), None)
+            .expect("invalid metrics prefix");
+        let metrics = Self {
+            mqtt_messages: IntCounterVec::new(
+                opts!("mqtt_messages_total", "MQTT messages sent and received."),
+                &["direction", "topic"],
+            )
+            .expect("invalid metric"),
+            cec_frames: IntCounterVec::new(
+                opts!("cec_frames_total", "CEC frames sent and received."),
+                &["direction", "opcode"],
+            )
+            .expect("invalid metric"),
+            cec_transmits: IntCounterVec::new(
+                opts!(
+                    "cec_transmits_total",
+                    "CEC frames we transmitted, as \"sent\", and the ones nobody acknowledged, as \"nack\"."
+                ),
+                &["result"],
+            )
+            .expect("invalid metric"),
+            command_duration: HistogramVec::new(
+                histogram_opts!(
+                    "command_duration_seconds",
+                    "How long a command for cec-client waited, from when we sent it to cec-client reading it. This includes the commands queued up ahead of it."
+                ),
+                &["command"],
+            )
+            .expect("invalid metric"),
+            cec_client_starts: IntCounter::new(
+                "cec_client_starts_total",
+                "How many times cec-client was started.",
+            )
+            .expect("invalid metric"),
+            tv_power: IntGauge::new("tv_power", "1 if the TV is on, and 0 if it is off.")
+                .expect("invalid metric"),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 6] = [
+            Box::new(metrics.mqtt_messages.clone()),
+            Box::new(metrics.cec_frames.clone()),
+            Box::new(metrics.cec_transmits.clone()),
+            Box::new(metrics.command_duration.clone()),
+            Box::new(metrics.cec_client_starts.clone()),
+            Box::new(metrics.tv_power.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("could not register metric");
+        }
+        return metrics;
+    }
+
+    pub fn mqtt_received(&self, topic: &str) {
+        self.mqtt_messages.with_label_values(&["in", topic]).inc();
+    }
+
+    pub fn mqtt_published(&self, topic: &str) {
+        self.mqtt_messages.with_label_values(&["out", topic]).inc();
+    }
+
+    /// count a frame from the traffic log. cec-client logs the frames we send before it knows whether they were acknowledged, so those only count as sent, and cec_transmit_failed() counts the ones that weren't.
+    pub fn cec_frame(&self, frame: &CecFrame) {
+        let direction = match frame.direction {
+            Direction::Sent => "out",
+            Direction::Received => "in",
+        };
+        self.cec_frames
+            .with_label_values(&[direction, frame.opcode_name()])
+            .inc();
+        if frame.direction == Direction::Sent {
+            self.cec_transmits.with_label_values(&["sent"]).inc();
+        }
+    }
+
+    pub fn cec_transmit_failed(&self) {
+        self.cec_transmits.with_label_values(&["nack"]).inc();
+    }
+
+    /// cec-client took a command, like "tx" or "volup", 'duration' after we sent it.
+    pub fn command_sent(&self, command: &str, duration: Duration) {
+        self.command_duration
+            .with_label_values(&[command])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn cec_client_started(&self) {
+        self.cec_client_starts.inc();
+    }
+
+    /// the TV's power state, as "ON" or "OFF". Anything else leaves the gauge as it was.
+    pub fn tv_power(&self, state: &str) {
+        match state {
+            "ON" => self.tv_power.set(1),
+            "OFF" => self.tv_power.set(0),
+            _ => {}
+        }
+    }
+
+    /// everything, in the prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("could not encode metrics");
+        return String::from_utf8_lossy(&buffer).to_string();
+    }
+}
+
+#[test]
+fn rendering_metrics() {
+    let frame = CecFrame::parse_traffic("TRAFFIC: [  4125]\t>> 0f:36").expect("no frame");
+    metrics().cec_frame(&frame);
+    let frame = CecFrame::parse_traffic("TRAFFIC: [  4130]\t<< 10:8f").expect("no frame");
+    metrics().cec_frame(&frame);
+    metrics().command_sent("tx", Duration::from_millis(20));
+
+    let rendered = metrics().render();
+    assert!(rendered.contains(r#"hdmicec2mqtt_cec_frames_total{direction="in",opcode="Standby"}"#));
+    // whether it was acknowledged isn't known yet.
+    assert!(rendered.contains(r#"hdmicec2mqtt_cec_transmits_total{result="sent"}"#));
+    assert!(!rendered.contains(r#"result="ack""#));
+    assert!(rendered.contains(r#"hdmicec2mqtt_command_duration_seconds_count{command="tx"}"#));
+    assert!(rendered.contains("hdmicec2mqtt_tv_power"));
+}
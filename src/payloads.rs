@@ -1,6 +1,9 @@
-use serde::Serialize;
+use std::collections::BTreeMap;
 
-use crate::ha_entity::{Device, DeviceClass};
+use serde::{Deserialize, Serialize};
+
+use crate::command::CommandSchema;
+use crate::ha_entity::Device;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct DevicePayload {
@@ -36,6 +39,106 @@ impl Default for OriginPayload {
     }
 }
 
+/// Whether an entity is shown with the device's controls, or tucked away in its configuration or diagnostic sections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[allow(dead_code)]
+pub enum EntityCategory {
+    Config,
+    Diagnostic,
+}
+
+/// How homeassistant keeps long term statistics for a sensor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[allow(dead_code)]
+pub enum StateClass {
+    Measurement,
+    Total,
+    TotalIncreasing,
+}
+
+/// One of the topics that decides if an entity is available.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AvailabilityPayload {
+    pub topic: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_available: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_not_available: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_template: Option<String>,
+}
+
+impl AvailabilityPayload {
+    pub fn new(topic: &str) -> Self {
+        return Self {
+            topic: topic.to_string(),
+            payload_available: None,
+            payload_not_available: None,
+            value_template: None,
+        };
+    }
+}
+
+/// The optional parts of the discovery payload, which entities can set through the Entity builder. Everything left as None is left out, so homeassistant uses its own default. See https://www.home-assistant.io/integrations/mqtt for what each of these does.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DiscoveryOptions {
+    /// the name homeassistant shows. This goes in the payload's own name, which is the id otherwise.
+    #[serde(skip)]
+    pub name: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<EntityCategory>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled_by_default: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_template: Option<String>,
+
+    /// extra availability topics, on top of the device's own availability topic.
+    #[serde(skip)]
+    pub availability: Vec<AvailabilityPayload>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qos: Option<u8>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retain: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub optimistic: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_on: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_off: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit_of_measurement: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_class: Option<StateClass>,
+
+    /// in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expire_after: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_template: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct ConfigPayload {
     name: Option<String>,
@@ -50,10 +153,29 @@ pub struct ConfigPayload {
     command_topic: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    device_class: Option<String>, //TODO limit to all available device classes via enum!
+    payload_on: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    value_template: Option<String>,
+    payload_off: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_press: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    availability_topic: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    availability: Option<Vec<AvailabilityPayload>>,
+
+    /// with more than one availability topic, the entity is only available if all of them say so.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    availability_mode: Option<String>,
+
+    #[serde(flatten)]
+    options: DiscoveryOptions,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     unique_id: Option<String>,
@@ -70,25 +192,217 @@ impl ConfigPayload {
         state_topic: Option<String>,
         command_topic: Option<String>,
         device: &Device,
-        device_class: &DeviceClass,
+        device_class: Option<String>,
         id: &str,
+        schema: Option<&CommandSchema>,
+        options: &DiscoveryOptions,
     ) -> Self {
-        let device_class = if *device_class == DeviceClass::None {
-            None
-        } else {
-            Some(device_class.to_string())
-        };
+        // homeassistant doesn't allow both availability_topic and availability, so we only use the list if there is more than our own topic.
+        let (availability_topic, availability, availability_mode) =
+            if options.availability.is_empty() {
+                (Some(device.availability_topic()), None, None)
+            } else {
+                let mut availability = vec![AvailabilityPayload::new(&device.availability_topic())];
+                availability.extend(options.availability.iter().cloned());
+                (None, Some(availability), Some("all".to_string()))
+            };
 
         Self {
-            name: Some(id.to_string()),
+            name: Some(options.name.clone().unwrap_or_else(|| id.to_string())),
             state_topic,
             command_topic,
+            payload_on: schema.and_then(|schema| schema.payload_on()),
+            payload_off: schema.and_then(|schema| schema.payload_off()),
+            payload_press: schema.and_then(|schema| schema.payload_press()),
             device_class,
             unique_id: Some(format!("{}_{}", device.unique_id, id)),
             origin: Some(OriginPayload::default()),
+            availability_topic,
+            availability,
+            availability_mode,
             device: Some(DevicePayload::from_device(device)),
             object_id: None,
-            value_template: None,
+            options: options.clone(),
         }
     }
+
+    /// leave out the device and origin, which a device discovery message only includes once, for all of its components.
+    pub fn for_component(mut self) -> Self {
+        self.device = None;
+        self.origin = None;
+        return self;
+    }
+}
+
+/// One entity in a device discovery message. A component with only a platform is removed from the device.
+#[derive(Debug, Clone, Serialize)]
+pub struct ComponentPayload {
+    platform: String,
+
+    #[serde(flatten)]
+    config: Option<ConfigPayload>,
+}
+
+/// A whole device and all of its entities, for device based discovery. See https://www.home-assistant.io/integrations/mqtt/#device-discovery-payload
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceDiscoveryPayload {
+    device: DevicePayload,
+    origin: OriginPayload,
+    components: BTreeMap<String, ComponentPayload>,
+}
+
+impl DeviceDiscoveryPayload {
+    pub fn new(device: &Device) -> Self {
+        return Self {
+            device: DevicePayload::from_device(device),
+            origin: OriginPayload::default(),
+            components: BTreeMap::new(),
+        };
+    }
+
+    /// add the entity announced on 'discovery_topic' as a component.
+    pub fn add_component(&mut self, discovery_topic: &str, config: ConfigPayload) {
+        component_of(discovery_topic).map(|(platform, id)| {
+            self.components.insert(
+                id,
+                ComponentPayload {
+                    platform,
+                    config: Some(config.for_component()),
+                },
+            );
+        });
+    }
+
+    /// remove the entity once announced on 'discovery_topic' from homeassistant, unless it is still added as a component.
+    pub fn remove_component(&mut self, discovery_topic: &str) {
+        component_of(discovery_topic).map(|(platform, id)| {
+            self.components.entry(id).or_insert(ComponentPayload {
+                platform,
+                config: None,
+            });
+        });
+    }
+}
+
+/// the platform and component id of an entity, taken from its own discovery topic, "<prefix>/<platform>/<id>/config".
+fn component_of(discovery_topic: &str) -> Option<(String, String)> {
+    let mut parts = discovery_topic.rsplit('/');
+    if parts.next()? != "config" {
+        return None;
+    }
+    let id = parts.next()?;
+    let platform = parts.next()?;
+    return Some((platform.to_string(), id.to_string()));
+}
+
+/// An entity we added, as recorded in the retained entity list.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntityRecordPayload {
+    pub discovery_topic: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub state_topic: Option<String>,
+}
+
+/// Every entity we added, retained on the broker so the next run can clean up after entities that no longer exist.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntityListPayload {
+    pub entities: Vec<EntityRecordPayload>,
+
+    /// whether the entities were announced with device based discovery.
+    #[serde(default)]
+    pub device_discovery: bool,
+
+    /// discovery topics of entities that are gone, but that no device discovery message has removed yet.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<String>,
+}
+
+impl EntityListPayload {
+    /// add everything in 'other' to this list, like when it comes from more than one place.
+    pub fn extend(&mut self, other: EntityListPayload) {
+        self.device_discovery |= other.device_discovery;
+        self.entities.extend(other.entities);
+        self.removed.extend(other.removed);
+    }
+}
+
+#[test]
+fn serializing_discovery_options() {
+    let device = Device {
+        unique_id: "test".to_string(),
+        name: None,
+        object_id: None,
+        topic_prefix: "homeassistant".to_string(),
+    };
+    let options = DiscoveryOptions {
+        icon: Some("mdi:television".to_string()),
+        entity_category: Some(EntityCategory::Diagnostic),
+        state_class: Some(StateClass::TotalIncreasing),
+        expire_after: Some(60),
+        ..DiscoveryOptions::default()
+    };
+    let payload = ConfigPayload::new(None, None, &device, None, "tv", None, &options);
+    let json = serde_json::to_value(&payload).expect("could not serialize payload");
+    assert_eq!(json["icon"], "mdi:television");
+    assert_eq!(json["entity_category"], "diagnostic");
+    assert_eq!(json["state_class"], "total_increasing");
+    assert_eq!(json["expire_after"], 60);
+    assert_eq!(
+        json["availability_topic"],
+        "homeassistant/test/availability"
+    );
+    assert!(json.get("availability").is_none());
+    assert!(json.get("retain").is_none());
+
+    // extra availability topics switch us over to the availability list.
+    let options = DiscoveryOptions {
+        availability: vec![AvailabilityPayload::new("zigbee2mqtt/bridge/state")],
+        ..DiscoveryOptions::default()
+    };
+    let payload = ConfigPayload::new(None, None, &device, None, "tv", None, &options);
+    let json = serde_json::to_value(&payload).expect("could not serialize payload");
+    assert!(json.get("availability_topic").is_none());
+    assert_eq!(
+        json["availability"][0]["topic"],
+        "homeassistant/test/availability"
+    );
+    assert_eq!(json["availability"][1]["topic"], "zigbee2mqtt/bridge/state");
+    assert_eq!(json["availability_mode"], "all");
+}
+
+#[test]
+fn serializing_device_discovery() {
+    let device = Device {
+        unique_id: "test".to_string(),
+        name: None,
+        object_id: None,
+        topic_prefix: "homeassistant".to_string(),
+    };
+    let config = ConfigPayload::new(
+        None,
+        None,
+        &device,
+        Some("switch".to_string()),
+        "tv",
+        None,
+        &DiscoveryOptions::default(),
+    );
+
+    let mut payload = DeviceDiscoveryPayload::new(&device);
+    payload.remove_component("homeassistant/switch/test_tv/config");
+    payload.add_component("homeassistant/switch/test_tv/config", config);
+    payload.remove_component("homeassistant/button/test_gone/config");
+    let json = serde_json::to_value(&payload).expect("could not serialize payload");
+
+    assert_eq!(json["device"]["identifiers"][0], "test");
+    assert_eq!(json["origin"]["name"], "hdmicec2mqtt");
+    let tv = &json["components"]["test_tv"];
+    assert_eq!(tv["platform"], "switch");
+    assert_eq!(tv["device_class"], "switch");
+    assert_eq!(tv["unique_id"], "test_tv");
+    assert!(tv.get("device").is_none());
+    assert_eq!(
+        json["components"]["test_gone"],
+        serde_json::json!({"platform": "button"})
+    );
 }
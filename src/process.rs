@@ -1,65 +1,172 @@
-use std::{
-    io::{BufRead, BufReader, Write},
-    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
-    thread::{self},
+use std::{io, process::Stdio, time::Duration};
+
+use log::{debug, info, warn};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    process::{Child, Command},
+    task::JoinHandle,
 };
 
-use anyhow::Context;
-use log::debug;
+use crate::capture::{CaptureDirection, CaptureLine, CaptureWriter};
+use crate::error::BridgeError;
+
+pub type ProcessInput = Box<dyn AsyncWrite + Send + Unpin>;
+pub type ProcessOutput = Box<dyn AsyncRead + Send + Unpin>;
 
 pub struct CommandProcess {
-    input: ChildStdin,
-    output: Option<ChildStdout>,
+    input: ProcessInput,
+    output: Option<ProcessOutput>,
     // we need a place to keep the child process reference. not sure what happens if we drop it.
-    #[allow(dead_code)]
-    child: Child,
+    // replayed or simulated processes don't have one.
+    child: Option<Child>,
+    capture: Option<CaptureWriter>,
 }
 
 impl CommandProcess {
-    pub fn new(command: &mut Command) -> Self {
+    /// start up a new process. This needs to be called from inside the tokio runtime.
+    pub fn new(command: &mut Command) -> Result<Self, BridgeError> {
         let mut child = command
             .stdout(Stdio::piped())
             .stdin(Stdio::piped())
+            .kill_on_drop(true)
             .spawn()
-            .with_context(|| format!("{:?}", command.get_program()))
-            .expect("could not open process");
+            .map_err(|source| BridgeError::ProcessStart {
+                program: command.as_std().get_program().to_string_lossy().to_string(),
+                source,
+            })?;
+
+        return Ok(Self {
+            input: Box::new(child.stdin.take().unwrap()),
+            output: child
+                .stdout
+                .take()
+                .map(|output| Box::new(output) as ProcessOutput),
+            child: Some(child),
+            capture: None,
+        });
+    }
 
+    /// Wrap something that acts like a process, but isn't one, like a simulator.
+    pub fn from_io(input: ProcessInput, output: ProcessOutput) -> Self {
         return Self {
-            input: child.stdin.take().unwrap(),
-            output: child.stdout.take(),
-            child: child,
+            input,
+            output: Some(output),
+            child: None,
+            capture: None,
         };
     }
 
-    pub fn send(&mut self, input: &str) -> Result<usize, std::io::Error> {
+    /// Play back the output of a capture file, as if it came from a real process. 'speed' is a multiplier on how fast to replay, and can be infinite to replay without waiting at all. Any input sent to this process is discarded.
+    pub fn replay(path: &str, speed: f64) -> Result<Self, io::Error> {
+        if speed.is_nan() || speed <= 0.0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can't replay at {speed}x speed"),
+            ));
+        }
+        let lines = CaptureLine::read_file(path)?;
+        let (reader, mut writer) = tokio::io::duplex(4096);
+
+        info!(
+            "replaying {} lines from {path} at {speed}x speed",
+            lines.len()
+        );
+        tokio::spawn(async move {
+            let mut elapsed = std::time::Duration::ZERO;
+            for line in lines
+                .iter()
+                .filter(|line| line.direction == CaptureDirection::Output)
+            {
+                let gap = line.timestamp.saturating_sub(elapsed).as_secs_f64() / speed;
+                // a slow enough speed waits longer than a Duration can hold, which is forever anyway.
+                tokio::time::sleep(Duration::try_from_secs_f64(gap).unwrap_or(Duration::MAX)).await;
+                elapsed = elapsed.max(line.timestamp);
+                let written = writer
+                    .write_all(format!("{}\n", line.line).as_bytes())
+                    .await;
+                if written.is_err() {
+                    break;
+                }
+            }
+            debug!("finished replaying capture");
+        });
+
+        return Ok(Self::from_io(Box::new(tokio::io::sink()), Box::new(reader)));
+    }
+
+    /// record every line sent to, or read from the process in a capture file. This needs to be called before with_output() to record the output.
+    pub fn capture_to(&mut self, path: &str) -> Result<(), io::Error> {
+        info!("capturing process input and output to {path}");
+        self.capture = Some(CaptureWriter::create(path)?);
+        return Ok(());
+    }
+
+    pub async fn send(&mut self, input: &str) -> Result<(), std::io::Error> {
         debug!("sending to process: {}", input);
-        return self.input.write(input.as_bytes());
+        self.capture.as_ref().map(|capture| {
+            capture.record(CaptureDirection::Input, input);
+        });
+        self.input.write_all(input.as_bytes()).await?;
+        return self.input.flush().await;
     }
 
+    /// call 'func' with every line of output from the process, on a separate task. That task finishes once the output closes, usually because the process exited.
     pub fn with_output<F: 'static + FnMut(String) -> () + Send>(
         &mut self,
-        func: F,
-    ) -> Result<(), &str> {
+        mut func: F,
+    ) -> Result<JoinHandle<()>, BridgeError> {
         if self.output.is_some() {
-            debug!("spawning reader thread...");
-            let reader = BufReader::new(self.output.take().unwrap());
-            thread::spawn(|| {
-                reader.lines().filter_map(|line| line.ok()).for_each(func);
+            debug!("spawning reader task...");
+            let mut output = BufReader::new(self.output.take().unwrap());
+            let capture = self.capture.clone();
+            let reader = tokio::spawn(async move {
+                let mut buffer = vec![];
+                // device names are raw bytes off the bus, so a line that isn't utf-8 is read as best we can, instead of ending the output.
+                while let Ok(read) = output.read_until(b'\n', &mut buffer).await {
+                    if read == 0 {
+                        break;
+                    }
+                    let line = String::from_utf8_lossy(&buffer)
+                        .trim_end_matches(['\n', '\r'])
+                        .to_string();
+                    buffer.clear();
+                    capture.as_ref().map(|capture| {
+                        capture.record(CaptureDirection::Output, &line);
+                    });
+                    func(line);
+                }
+                debug!("process output closed");
             });
-            return Ok(());
+            return Ok(reader);
         } else {
-            return Err("Can not read from output twice! output is already taken!");
+            return Err(BridgeError::OutputTaken);
         }
     }
 
-    pub fn kill(&mut self) -> Result<(), std::io::Error> {
-        return self.child.kill();
+    /// ask the process to exit by sending 'command', and wait up to 'timeout' for it to happen. If it doesn't, the process is killed.
+    pub async fn quit(&mut self, command: &str, timeout: Duration) -> Result<(), std::io::Error> {
+        self.send(command).await?;
+        let child = match self.child.as_mut() {
+            Some(child) => child,
+            None => return Ok(()),
+        };
+        match tokio::time::timeout(timeout, child.wait()).await {
+            Ok(status) => {
+                debug!("process exited with {}", status?);
+                return Ok(());
+            }
+            Err(_) => {
+                warn!("process did not exit in time. killing it.");
+                return child.kill().await;
+            }
+        }
     }
 }
 
-#[test]
-fn read_output() {
-    let mut process = CommandProcess::new(Command::new("echo").arg("Hello World!"));
+#[tokio::test]
+async fn read_output() {
+    let mut process = CommandProcess::new(Command::new("echo").arg("Hello World!"))
+        .expect("could not start process");
 
     process
         .with_output(|line| {
@@ -68,17 +175,34 @@ fn read_output() {
         .expect("could not setup output");
 }
 
-#[test]
-fn send_input_and_read_output() {
+#[tokio::test]
+async fn process_errors() {
+    let missing = CommandProcess::new(&mut Command::new("hdmicec2mqtt-does-not-exist"));
+    assert!(matches!(missing, Err(BridgeError::ProcessStart { .. })));
+
+    let mut process =
+        CommandProcess::new(&mut Command::new("cat")).expect("could not start process");
+    process
+        .with_output(|_line| {})
+        .expect("could not setup output");
+    assert!(matches!(
+        process.with_output(|_line| {}),
+        Err(BridgeError::OutputTaken)
+    ));
+}
+
+#[tokio::test]
+async fn send_input_and_read_output() {
     use std::cell::Cell;
     use std::sync::{Arc, Mutex};
     use std::time::Duration;
 
     // make a new process using cat: we will send some input, and expect the same
     // content as output to be read.
-    let mut process = CommandProcess::new(&mut Command::new("cat"));
+    let mut process =
+        CommandProcess::new(&mut Command::new("cat")).expect("could not start process");
 
-    let lines_read_cell = Arc::new(Mutex::new(Cell::new(0 as usize)));
+    let lines_read_cell = Arc::new(Mutex::new(Cell::new(0_usize)));
     let lines_read_clone = lines_read_cell.clone();
 
     // setup the listener, with our expectations.
@@ -88,7 +212,7 @@ fn send_input_and_read_output() {
     process
         .with_output(move |line| {
             assert_eq!(line, "Hello World!");
-            lines_read = lines_read + 1;
+            lines_read += 1;
 
             let lines = lines_read_clone.lock().expect("could not take lock");
             lines.replace(lines_read);
@@ -98,14 +222,102 @@ fn send_input_and_read_output() {
     // send a known input.
     process
         .send("Hello World!\n")
+        .await
         .expect("could not send message");
 
-    // wait for a little bit, for the reader thread to process
+    // wait for a little bit, for the reader task to process
     // the input, and gather the output from the process.
-    thread::sleep(Duration::from_millis(500));
+    tokio::time::sleep(Duration::from_millis(500)).await;
 
     // and finally, read the number of lines, and ensure that we
     // read the correct number of lines.
     let lines_read_usize: usize = lines_read_cell.lock().expect("could not take lock").take();
     assert_eq!(lines_read_usize, 1);
 }
+
+#[tokio::test]
+async fn capture_input_and_output() {
+    use std::time::Duration;
+
+    let path = crate::test_harness::temp_path("capture_input_and_output.cap");
+    let path = path.to_str().expect("temp dir is not utf-8");
+
+    let mut process =
+        CommandProcess::new(&mut Command::new("cat")).expect("could not start process");
+    process.capture_to(path).expect("could not create capture");
+    process
+        .with_output(|_line| {})
+        .expect("could not setup output");
+    process
+        .send("pow 0.0.0.0\n")
+        .await
+        .expect("could not send message");
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let lines = CaptureLine::read_file(path).expect("could not read capture");
+    let lines: Vec<(CaptureDirection, &str)> = lines
+        .iter()
+        .map(|line| (line.direction, line.line.as_str()))
+        .collect();
+    assert_eq!(
+        lines,
+        vec![
+            (CaptureDirection::Input, "pow 0.0.0.0"),
+            (CaptureDirection::Output, "pow 0.0.0.0")
+        ]
+    );
+}
+
+#[tokio::test]
+async fn replay_output() {
+    use tokio::sync::mpsc;
+
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/captures/power_cycle.cap"
+    );
+    let mut process = CommandProcess::replay(path, f64::INFINITY).expect("could not replay");
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    process
+        .with_output(move |line| sender.send(line).expect("could not send line"))
+        .expect("could not setup output");
+
+    // the reader task (and our sender) is dropped once the whole capture is replayed.
+    let mut lines: Vec<String> = vec![];
+    while let Some(line) = receiver.recv().await {
+        lines.push(line);
+    }
+    assert_eq!(lines.len(), 6);
+    assert_eq!(lines[0], "power status: standby");
+    assert_eq!(lines[5], "TRAFFIC: [         15120]\t>> 0f:36");
+
+    for speed in [0.0, -1.0, f64::NAN] {
+        assert!(CommandProcess::replay(path, speed).is_err());
+    }
+}
+
+#[tokio::test]
+async fn reading_output_that_is_not_utf8() {
+    use tokio::sync::mpsc;
+
+    let (input, _) = tokio::io::duplex(64);
+    let (mut writer, output) = tokio::io::duplex(64);
+    let mut process = CommandProcess::from_io(Box::new(input), Box::new(output));
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    process
+        .with_output(move |line| sender.send(line).expect("could not send line"))
+        .expect("could not setup output");
+
+    writer
+        .write_all(b"osd name: TV\xff\r\nwaiting for input\n")
+        .await
+        .expect("could not write output");
+    drop(writer);
+    let mut lines: Vec<String> = vec![];
+    while let Some(line) = receiver.recv().await {
+        lines.push(line);
+    }
+    assert_eq!(lines, vec!["osd name: TV\u{fffd}", "waiting for input"]);
+}
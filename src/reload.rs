@@ -0,0 +1,71 @@
+//! Reloading the config while the bridge is running, without restarting cec-client. A reload happens on SIGHUP, or when the config file changes.
+use std::time::{Duration, SystemTime};
+
+use log::{error, info};
+use tokio::sync::mpsc;
+
+use crate::args::Args;
+use crate::config::Config;
+
+/// how often to check whether the config file changed.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Watch for reasons to reload, and send the new config every time. It is read just like on startup, with the same overrides, and a config that isn't valid is logged and skipped, so the bridge keeps running with the old one.
+pub fn watch(args: Args) -> mpsc::UnboundedReceiver<Config> {
+    let (triggers, mut reasons) = mpsc::unbounded_channel();
+    #[cfg(unix)]
+    tokio::spawn(hangups(triggers.clone()));
+    tokio::spawn(file_changes(args.config_path().to_string(), triggers));
+
+    let (sender, reloads) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(reason) = reasons.recv().await {
+            info!("{reason}, reloading the config");
+            match args.load_config() {
+                Ok(config) => {
+                    if sender.send(config).is_err() {
+                        return;
+                    }
+                }
+                Err(err) => error!("not reloading: {err:#}"),
+            }
+        }
+    });
+    return reloads;
+}
+
+/// SIGHUP is the usual way to ask a daemon to reload, like `docker kill -s HUP`.
+#[cfg(unix)]
+async fn hangups(triggers: mpsc::UnboundedSender<String>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).expect("could not listen for SIGHUP");
+    while hangup.recv().await.is_some() {
+        if triggers.send("got SIGHUP".to_string()).is_err() {
+            return;
+        }
+    }
+}
+
+/// poll the config file's modification time. This also notices a file that was missing when we started.
+async fn file_changes(path: String, triggers: mpsc::UnboundedSender<String>) {
+    let mut modified = modified_time(&path);
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let current = modified_time(&path);
+        if current == modified || current.is_none() {
+            continue;
+        }
+        modified = current;
+        if triggers.send(format!("{path} changed")).is_err() {
+            return;
+        }
+    }
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    return std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok();
+}
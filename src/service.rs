@@ -1,89 +1,308 @@
-use std::{collections::HashMap, sync::Arc};
+// faux names the lifetimes in the mocks it generates for StateManager.
+#![cfg_attr(test, allow(mismatched_lifetime_syntaxes))]
 
-use anyhow::{anyhow, Context, Error};
-use log::{debug, error, info, trace};
-use rumqttc::{Client, Connection, Event, Incoming, Publish, QoS};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::Duration,
+};
 
-use crate::{config::Config, ha_entity::HaMqttEntity};
+use anyhow::{anyhow, Error};
+use log::{debug, error, info, trace, warn};
+use rumqttc::{
+    AsyncClient, ConnectionError, Event, EventLoop, Incoming, LastWill, Outgoing, Publish, QoS,
+};
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time::Instant,
+};
+
+use crate::{
+    config::Config,
+    entity_store::EntityStore,
+    error::BridgeError,
+    ha_entity::{Device, HaMqttEntity},
+    metrics::metrics,
+    payloads::{DeviceDiscoveryPayload, EntityListPayload, EntityRecordPayload},
+};
 
 const MAX_ERROR_COUNT: usize = 10;
 
+/// how long to wait before trying to reconnect after a connection error.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// how long to wait for retained messages after the broker acknowledges our subscription.
+const RESTORE_GRACE: Duration = Duration::from_millis(250);
+
+/// how long to wait for retained messages at most, in case the broker never acknowledges us.
+const RESTORE_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// A way for entities to update their state, without accessing the HaBroker. Entities can easily clone and own a copy of this object.
 #[cfg_attr(test, faux::create)]
 #[derive(Clone)]
 pub struct StateManager {
-    client: Arc<Client>,
+    client: AsyncClient,
     state_topic: String,
     entity_name: String,
+    retain: bool,
 }
 
 #[cfg_attr(test, faux::methods)]
 impl StateManager {
     /// create a new StateManager for a given state topic, and mqtt client reference
-    pub fn new(client: Arc<Client>, state_topic: String, entity_name: String) -> Self {
+    pub fn new(
+        client: AsyncClient,
+        state_topic: String,
+        entity_name: String,
+        retain: bool,
+    ) -> Self {
         Self {
             client,
             state_topic,
             entity_name,
+            retain,
         }
     }
 
     /// update the entities state via the topic in the constructor. 'state' is the entire message payload, possibly JSON formatted. For simple switches, this may just be the string "ON" or "OFF". See Homeassistant docs for more info on what to send.
+    /// This never blocks, so it is safe to call from anywhere.
     pub fn update_state(&self, state: String) {
-        self.client
-            .publish(&self.state_topic, QoS::AtLeastOnce, false, state)
-            .with_context(|| {
-                format!(
-                    "entity: \"{}\" topic: \"{}\"  ",
-                    self.entity_name, self.state_topic
-                )
-            })
-            .expect("could not publish entities state message");
+        // this fails if the client's request channel is full. The state will be
+        // corrected on the next update, so it's not worth taking down the process for.
+        let published = self
+            .client
+            .try_publish(&self.state_topic, QoS::AtLeastOnce, self.retain, state)
+            .map_err(|source| BridgeError::Publish {
+                topic: self.state_topic.clone(),
+                source,
+            });
+        match published {
+            Ok(_) => metrics().mqtt_published(&self.state_topic),
+            Err(err) => error!(
+                "could not update the state of \"{}\": {err}",
+                self.entity_name
+            ),
+        }
     }
 }
 
 /// A representation of the connection to the MQTT Broker and HomeAssistant. Many entities or devices can be added to the same broker instance.
 pub struct HaBroker {
-    // we will want to share Client with other threads that might be updating entity state.
-    client: Arc<Client>,
+    // cloning the client is cheap, and entities will want their own copies for updating their state.
+    client: AsyncClient,
     config: Config,
-    connection: Option<Connection>,
+    notifications: mpsc::UnboundedReceiver<Result<Event, ConnectionError>>,
+    /// notifications that arrived while we were busy restoring, and still need to be handled by listen().
+    pending: VecDeque<Result<Event, ConnectionError>>,
+    /// how many subscribe requests we have made, and how many of them have gone out. restore() uses them to find the packet id of its own subscription.
+    subscriptions: usize,
+    subscriptions_sent: usize,
+    /// how many unsubscribe requests we have made, and how many of them have gone out. say_goodbye() uses them to tell its own messages apart.
+    unsubscriptions: usize,
+    unsubscriptions_sent: usize,
+    connection: JoinHandle<()>,
+    /// whether we are connected to the broker right now, as far as listen() can tell.
+    connected: watch::Sender<bool>,
+    availability_topic: String,
+    error_topic: String,
+    entities_topic: String,
+    store: Option<EntityStore>,
+    /// with device based discovery, entities that no longer exist are removed by the next device discovery message.
+    removed_components: Vec<String>,
+    /// the entities an earlier connection announced, before reconnect(). restore() treats these like the retained list.
+    announced: EntityListPayload,
     entities: HashMap<String, Box<dyn HaMqttEntity>>,
     topic_map: HashMap<String, Vec<String>>,
 }
 
 impl HaBroker {
-    /// get a copy of a reference to the client. useful if you want to publish messages to MQTT directly.
+    /// get a copy of the client. useful if you want to publish messages to MQTT directly.
     #[allow(dead_code)]
-    pub fn client(&self) -> Arc<Client> {
+    pub fn client(&self) -> AsyncClient {
         self.client.clone()
     }
 
-    /// Create a new connection from a given config object. Automatically opens a new mqtt connection.
+    /// watch whether we are connected to the broker.
+    pub fn connected(&self) -> watch::Receiver<bool> {
+        return self.connected.subscribe();
+    }
+
+    /// Create a new connection from a given config object. Automatically opens a new mqtt connection, which is kept alive on a separate task. This needs to be called from inside the tokio runtime.
     pub fn from_config(config: Config) -> Self {
-        let mqtt_options = config.mqtt.as_mqtt_options();
+        let device = Device::from_config(&config);
+        let availability_topic = device.availability_topic();
+        let error_topic = device.error_topic();
+        let entities_topic = device.entities_topic();
+        let store = config.device.state_file.as_deref().map(EntityStore::new);
+        let mut mqtt_options = config.mqtt.as_mqtt_options();
+        // if we die without saying goodbye, the broker should tell homeassistant we are gone.
+        mqtt_options.set_last_will(LastWill::new(
+            &availability_topic,
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
         debug!("connection options: {:?}", mqtt_options);
-        let (client, connection) = Client::new(mqtt_options, config.mqtt.async_capacity);
-        let ha_broker = Self {
+        let (client, eventloop) = AsyncClient::new(mqtt_options, config.mqtt.async_capacity);
+        // this is unbounded, so the connection task never waits on us, even before listen() is called.
+        let (sender, notifications) = mpsc::unbounded_channel();
+        let connection = tokio::spawn(HaBroker::poll(eventloop, sender));
+
+        Self {
             entities: HashMap::new(),
             config,
-            client: Arc::new(client),
-            connection: Some(connection),
+            client,
+            notifications,
+            pending: VecDeque::new(),
+            subscriptions: 0,
+            subscriptions_sent: 0,
+            unsubscriptions: 0,
+            unsubscriptions_sent: 0,
+            connection,
+            connected: watch::Sender::new(false),
+            availability_topic,
+            error_topic,
+            entities_topic,
+            store,
+            removed_components: Vec::new(),
+            announced: EntityListPayload::default(),
             topic_map: HashMap::new(),
-        };
-        ha_broker
+        }
+    }
+
+    /// the config we are running with, including any reloads.
+    pub fn config(&self) -> &Config {
+        return &self.config;
+    }
+
+    /// Apply a new config while listening, with 'entities' to replace the ones we have. The mqtt connection is only replaced when its settings, or the device's topics, changed. cec-client and the http server keep running, so changes to them wait for a restart.
+    async fn apply_config<T: 'static + HaMqttEntity>(
+        &mut self,
+        mut config: Config,
+        entities: impl FnOnce(&Config) -> Vec<T>,
+    ) {
+        if config.cec != self.config.cec {
+            warn!("changes to the [cec] section take effect after a restart");
+            config.cec = self.config.cec.clone();
+        }
+        if config.http != self.config.http {
+            warn!("changes to the [http] section take effect after a restart");
+            config.http = self.config.http.clone();
+        }
+        // the last will is set when connecting, so a new availability topic needs a new connection too.
+        let reconnect = config.mqtt != self.config.mqtt
+            || Device::from_config(&config).availability_topic() != self.availability_topic;
+
+        let entities = entities(&config);
+        if reconnect {
+            info!("reloading the config, and reconnecting to mqtt...");
+            self.reconnect(config).await;
+            for entity in entities {
+                self.add_entity(entity).await;
+            }
+            self.restore().await;
+            if let Err(err) = self.subscribe_to_status().await {
+                self.report_error(err);
+            }
+        } else {
+            info!("reloading the config...");
+            self.reload(config, entities).await;
+        }
+        if self.config.topic.device_discovery {
+            self.send_all_discovery_messages().await;
+        }
+    }
+
+    /// Disconnect, and connect again with a new config. The entities we announced are passed along, so restore() can remove the ones that don't come back, and anyone watching connected() keeps watching the new connection.
+    async fn reconnect(&mut self, config: Config) {
+        let announced = self.current_entities();
+        let timeout = Duration::from_secs_f64(self.config.shutdown.timeout);
+        // the entities are coming back, so their states stay where they are.
+        if tokio::time::timeout(timeout, self.say_goodbye(false))
+            .await
+            .is_err()
+        {
+            warn!("timed out disconnecting from mqtt");
+        }
+        self.connected.send_replace(false);
+
+        let connected = self.connected.clone();
+        *self = HaBroker::from_config(config);
+        self.connected = connected;
+        self.announced = announced;
+    }
+
+    /// Swap our config and entities for new ones, without reconnecting. Entities that went away are removed from homeassistant, and the rest are announced again. The mqtt settings and the device's topics have to stay the same, or this needs reconnect() instead.
+    async fn reload<T: 'static + HaMqttEntity>(&mut self, config: Config, entities: Vec<T>) {
+        let previous = self.current_entities();
+        let command_topics: Vec<String> = self
+            .entities
+            .values()
+            .filter_map(|entity| entity.get_command_topic())
+            .collect();
+        for topic in command_topics {
+            self.try_unsubscribe(&topic);
+        }
+        let status_changed = config.topic.status != self.config.topic.status;
+        if status_changed {
+            self.try_unsubscribe(&self.config.topic.status.clone());
+        }
+        self.entities.clear();
+        self.topic_map.clear();
+        self.store = config.device.state_file.as_deref().map(EntityStore::new);
+        self.config = config;
+
+        for entity in entities {
+            self.add_entity(entity).await;
+        }
+        let current = self.current_entities();
+        self.migrate_discovery(&previous, &current).await;
+        self.remove_stale_entities(&previous, &current);
+        self.save_entity_list(&self.current_entities());
+        if status_changed {
+            if let Err(err) = self.subscribe_to_status().await {
+                self.report_error(err);
+            }
+        }
+    }
+
+    /// drive the mqtt connection, and pass along everything that happens to listen(). This keeps the connection alive, even while we are busy handling commands.
+    async fn poll(
+        mut eventloop: EventLoop,
+        notifications: mpsc::UnboundedSender<Result<Event, ConnectionError>>,
+    ) {
+        loop {
+            let notification = eventloop.poll().await;
+            let failed = notification.is_err();
+            // the disconnect packet has been flushed to the broker by the time we see this, so we are done.
+            let disconnected = matches!(notification, Ok(Event::Outgoing(Outgoing::Disconnect)));
+            if notifications.send(notification).is_err() || disconnected {
+                // nobody is listening anymore.
+                return;
+            }
+            if failed {
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
     }
 
     /// Add a new entity to homeassistant, via the mqtt discovery topics.
-    pub fn add_entity<T: 'static + HaMqttEntity>(&mut self, mut entity: T) {
+    pub async fn add_entity<T: 'static + HaMqttEntity>(&mut self, mut entity: T) {
         let id = entity.get_name();
 
+        // in monitor mode we can't send anything on the CEC bus, so don't offer anything that would try.
+        if self.config.cec.monitor && entity.get_command_topic().is_some() {
+            warn!("not adding entity \"{id}\": it accepts commands, but cec-client is in monitor mode");
+            return;
+        }
+
         // TODO should this happen only after we configure??
         if let Some(state_topic) = entity.get_state_topic() {
             entity.connect_state(StateManager::new(
                 self.client.clone(),
                 state_topic,
                 entity.get_name(),
+                self.config.mqtt.retain_state,
             ));
         };
 
@@ -91,8 +310,13 @@ impl HaBroker {
             self.add_topic_mapping(&command_topic, id.clone());
         };
 
-        self.send_discovery_message(&entity);
-        self.subscribe_to_command_topic(&entity);
+        // with device based discovery, every entity is announced at once when we start listening.
+        if !self.config.topic.device_discovery {
+            self.send_discovery_message(&entity).await;
+        }
+        if let Err(err) = self.subscribe_to_command_topic(&entity).await {
+            self.report_error(err);
+        }
 
         self.entities.insert(id, Box::new(entity));
     }
@@ -105,99 +329,388 @@ impl HaBroker {
         }
     }
 
-    fn send_discovery_message<T: 'static + HaMqttEntity + ?Sized>(&self, entity: &T) {
-        let discovery_payload = entity.get_config_payload();
-        let discovery_message: String = match serde_json::to_string(&discovery_payload) {
-            Ok(value) => value,
-            Err(err) => panic! {"cound not stringify the discovery payload! error={err}"},
-        };
-
-        debug!(
-            "publishing config to topic {}: {}",
-            entity.get_discovery_topic(),
-            discovery_message,
-        );
+    /// announce a single entity on its own discovery topic. With device based discovery, everything is announced at once by send_all_discovery_messages() instead.
+    async fn send_discovery_message<T: 'static + HaMqttEntity + ?Sized>(&self, entity: &T) {
+        for (topic, payload) in discovery_messages(&self.config, [entity], &[]) {
+            self.publish_discovery(&topic, &payload).await;
+        }
+    }
 
+    /// publish a discovery message, and return whether it was queued.
+    async fn publish_discovery(&self, topic: &str, payload: &str) -> bool {
+        debug!("publishing config to topic {topic}: {payload}");
         let config_published = self
             .client
             .publish(
-                entity.get_discovery_topic(),
+                topic,
                 QoS::ExactlyOnce,
                 false, // instead of retaining these messages, we will listen for the mqtt integration's birth/will messages, as per the docs: https://www.home-assistant.io/integrations/mqtt#use-the-birth-and-will-messages-to-trigger-discovery
-                discovery_message.as_str(),
+                payload,
             )
-            .with_context(|| {
-                format!(
-                    "unable to publish discovery message for entity {}",
-                    entity.get_name()
-                )
-            });
-
+            .await;
         match config_published {
-            Ok(_) => {}
+            Ok(_) => {
+                metrics().mqtt_published(topic);
+                return true;
+            }
             Err(err) => {
-                error!("{err}");
+                error!("unable to publish discovery message on \"{topic}\": {err}");
+                return false;
             }
         }
     }
 
-    fn subscribe_to_command_topic<T: 'static + HaMqttEntity + ?Sized>(&self, entity: &T) {
+    async fn subscribe_to_command_topic<T: 'static + HaMqttEntity + ?Sized>(
+        &mut self,
+        entity: &T,
+    ) -> Result<(), BridgeError> {
         if let Some(command_topic) = entity.get_command_topic() {
             self.client
-                .subscribe(command_topic, QoS::AtMostOnce)
-                .unwrap();
+                .subscribe(&command_topic, QoS::AtMostOnce)
+                .await
+                .map_err(|source| BridgeError::Subscribe {
+                    topic: command_topic,
+                    source,
+                })?;
+            self.subscriptions += 1;
         }
+        return Ok(());
     }
 
-    fn send_all_discovery_messages(&self) {
-        self.entities.iter().for_each(|(_name, entity)| {
-            self.send_discovery_message(entity.as_ref());
-        });
+    /// announce every entity. With device based discovery, this is one message for the whole device, which also removes any entities that went away.
+    async fn send_all_discovery_messages(&mut self) {
+        let entities = self.entities.values().map(|entity| entity.as_ref());
+        let mut published = true;
+        for (topic, payload) in discovery_messages(&self.config, entities, &self.removed_components)
+        {
+            published &= self.publish_discovery(&topic, &payload).await;
+        }
+        // the device discovery message removed them, so the next one doesn't have to.
+        if published && !self.removed_components.is_empty() {
+            self.removed_components.clear();
+            self.save_entity_list(&self.current_entities());
+        }
+    }
+
+    /// let homeassistant know our entities are moving between per-entity and device based discovery, so it keeps them instead of removing and re-adding them. See https://www.home-assistant.io/integrations/mqtt/#migration-from-single-component-to-device-based-discovery
+    async fn migrate_discovery(
+        &mut self,
+        previous: &EntityListPayload,
+        current: &EntityListPayload,
+    ) {
+        if previous.entities.is_empty()
+            || previous.device_discovery == self.config.topic.device_discovery
+        {
+            return;
+        }
+        let topics: Vec<String> = if self.config.topic.device_discovery {
+            // every entity we still have was announced on its own topic.
+            current
+                .entities
+                .iter()
+                .filter(|record| previous.entities.contains(record))
+                .map(|record| record.discovery_topic.clone())
+                .collect()
+        } else {
+            vec![Device::from_config(&self.config).discovery_topic()]
+        };
+        info!("migrating discovery of {} entities", current.entities.len());
+        for topic in topics {
+            let published = self.client.try_publish(
+                &topic,
+                QoS::ExactlyOnce,
+                false,
+                r#"{"migrate_discovery": true}"#,
+            );
+            if let Err(err) = published {
+                error!("could not migrate discovery on \"{topic}\": {err}");
+            }
+        }
+        if !self.config.topic.device_discovery {
+            // the entities were announced before we knew to migrate them, so announce them again.
+            self.send_all_discovery_messages().await;
+        }
     }
 
-    fn notify_entities(&mut self, event: &Publish) {
+    /// pass a command on to every entity listening on its topic. Every entity gets the command, even if an earlier one fails.
+    fn notify_entities(&mut self, event: &Publish) -> Result<(), BridgeError> {
         let u8_array = event.payload.iter().cloned().collect::<Vec<u8>>();
         let payload =
-            String::from_utf8(u8_array).expect("command payload  can not be parsed as utf_8");
+            String::from_utf8(u8_array).map_err(|source| BridgeError::InvalidPayload {
+                topic: event.topic.clone(),
+                source,
+            })?;
 
-        let matching_entities = self.topic_map.get(&event.topic);
-        match matching_entities {
-            Some(entity_indices) => entity_indices.iter().for_each(|name| {
-                let entity = self
-                    .entities
-                    .get_mut(name)
-                    .expect("invalid index into entities");
-                entity.on_command(&payload);
-            }),
-            None => {}
+        let mut result = Ok(());
+        for name in self.topic_map.get(&event.topic).into_iter().flatten() {
+            // the topic map and the entities are only changed together, so this shouldn't happen.
+            let Some(entity) = self.entities.get_mut(name) else {
+                warn!(
+                    "ignoring a command on {} for unknown entity \"{name}\"",
+                    event.topic
+                );
+                continue;
+            };
+            let handled = entity.on_command(&payload);
+            result = result.and(handled);
         }
+        return result;
     }
 
-    pub fn listen(&mut self) -> Result<(), Error> {
-        // we need to take ownership of "connection", so that we can continue to borrow from self.
-        let mut connection = self
-            .connection
-            .take()
-            .expect("connection is already being used!");
+    /// Read back what we retained on our last run. Each entity gets its old state back when retain_state is on, and entities that no longer exist are removed from homeassistant. This needs to be called after adding entities, and before listen().
+    pub async fn restore(&mut self) {
+        let previous = self.read_retained().await;
+        let current = self.current_entities();
+        self.migrate_discovery(&previous, &current).await;
+        self.remove_stale_entities(&previous, &current);
+        self.save_entity_list(&self.current_entities());
+    }
 
-        // subscribe to the homeassistant status topic to recieve birth/will messages. see https://www.home-assistant.io/integrations/mqtt#use-the-birth-and-will-messages-to-trigger-discovery
-        self.client
-            .subscribe(&self.config.topic.status, QoS::AtLeastOnce)
-            .with_context(|| format!("{}", &self.config.topic.status))
-            .expect("unable to subscribe to homeassistant's status topic");
+    /// record 'current' in the local store, and on the retained entity list, for the next run to clean up after.
+    fn save_entity_list(&self, current: &EntityListPayload) {
+        self.store.as_ref().map(|store| {
+            if let Err(err) = store.save(current) {
+                error!("could not save the entity list: {err}");
+            }
+        });
+        let payload = match serde_json::to_string(current) {
+            Ok(value) => value,
+            Err(err) => panic! {"could not stringify the entity list! error={err}"},
+        };
+        let published =
+            self.client
+                .try_publish(&self.entities_topic, QoS::AtLeastOnce, true, payload);
+        match published {
+            Ok(_) => metrics().mqtt_published(&self.entities_topic),
+            Err(err) => error!("could not publish the entity list: {err}"),
+        }
+    }
+
+    /// Remove every entity we ever announced from homeassistant, along with anything else we retained, and disconnect. Nothing should be added before calling this.
+    pub async fn purge(mut self) {
+        let previous = self.read_retained().await;
+        // read_retained() unsubscribes from what it read last, which marks where our messages start.
+        let start = self.unsubscriptions;
+        self.remove_stale_entities(&previous, &EntityListPayload::default());
+        // an empty device discovery message removes the device, along with all of its entities.
+        let device_topic = Device::from_config(&self.config).discovery_topic();
+        let device_topic = previous.device_discovery.then_some(&device_topic);
+        for topic in [&self.entities_topic, &self.availability_topic]
+            .into_iter()
+            .chain(device_topic)
+        {
+            let _ = self.client.try_publish(topic, QoS::AtLeastOnce, true, "");
+        }
+        self.store.as_ref().map(|store| {
+            if let Err(err) = store.remove() {
+                error!("could not remove the entity list: {err}");
+            }
+        });
+        info!("purged {} entities", previous.entities.len());
+        self.disconnect(start).await;
+    }
+
+    /// subscribe to everything we retain, and collect it. States are handed back to their entities, and the list of entities we announced before is returned, along with the ones in the local entity store.
+    async fn read_retained(&mut self) -> EntityListPayload {
+        let mut previous_entities = EntityListPayload::default();
+        self.store.as_ref().map(|store| match store.load() {
+            Ok(stored) => previous_entities.extend(stored),
+            Err(err) => warn!("ignoring the local entity list: {err}"),
+        });
+        previous_entities.extend(std::mem::take(&mut self.announced));
+
+        let mut state_topics: HashMap<String, String> = HashMap::new();
+        if self.config.mqtt.retain_state {
+            for (name, entity) in self.entities.iter() {
+                entity.get_state_topic().map(|topic| {
+                    state_topics.insert(topic, name.clone());
+                });
+            }
+        }
+        let mut topics: Vec<String> = state_topics.keys().cloned().collect();
+        topics.push(self.entities_topic.clone());
+
+        let filters = topics
+            .iter()
+            .map(|topic| rumqttc::SubscribeFilter::new(topic.clone(), QoS::AtLeastOnce));
+        if let Err(source) = self.client.subscribe_many(filters).await {
+            self.report_error(BridgeError::Subscribe {
+                topic: self.entities_topic.clone(),
+                source,
+            });
+            return previous_entities;
+        }
+        self.subscriptions += 1;
+        let ours = self.subscriptions;
+        debug!("reading retained messages from {topics:?}");
+
+        // our subscription is the last one we asked for. Retained messages follow right behind its acknowledgement.
+        let mut restore_pkid = None;
+        let mut deadline = Instant::now() + RESTORE_TIMEOUT;
+        let mut restored: HashSet<String> = HashSet::new();
+        while let Ok(Some(notification)) = tokio::time::timeout_at(deadline, self.receive()).await {
+            match &notification {
+                // requests go out in order, so ours is the one that brings the count up to it.
+                Ok(Event::Outgoing(Outgoing::Subscribe(pkid)))
+                    if self.subscriptions_sent == ours =>
+                {
+                    restore_pkid = Some(*pkid);
+                }
+                Ok(Event::Incoming(Incoming::SubAck(ack))) if Some(ack.pkid) == restore_pkid => {
+                    deadline = deadline.min(Instant::now() + RESTORE_GRACE);
+                }
+                Ok(Event::Incoming(Incoming::Publish(publish)))
+                    if publish.topic == self.entities_topic =>
+                {
+                    // an empty message means the list was purged.
+                    if !publish.payload.is_empty() {
+                        match serde_json::from_slice::<EntityListPayload>(&publish.payload) {
+                            Ok(entities) => previous_entities.extend(entities),
+                            Err(err) => warn!("ignoring invalid entity list: {err}"),
+                        }
+                    }
+                    continue;
+                }
+                Ok(Event::Incoming(Incoming::Publish(publish)))
+                    if state_topics.contains_key(&publish.topic) =>
+                {
+                    let name = &state_topics[&publish.topic];
+                    let state = String::from_utf8_lossy(&publish.payload);
+                    // an empty message is how retained states are cleared.
+                    if !state.is_empty() && restored.insert(name.clone()) {
+                        debug!("restoring state of \"{name}\": {state}");
+                        self.entities[name].restore_state(&state);
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            self.pending.push_back(notification);
+        }
+
+        for topic in topics {
+            if self.client.unsubscribe(topic).await.is_ok() {
+                self.unsubscriptions += 1;
+            }
+        }
+        return previous_entities;
+    }
+
+    /// the entities we have right now, as recorded in the entity list.
+    fn current_entities(&self) -> EntityListPayload {
+        let mut entities: Vec<EntityRecordPayload> = self
+            .entities
+            .values()
+            .map(|entity| EntityRecordPayload {
+                discovery_topic: entity.get_discovery_topic(),
+                state_topic: entity.get_state_topic(),
+            })
+            .collect();
+        entities.sort_by(|a, b| a.discovery_topic.cmp(&b.discovery_topic));
+        return EntityListPayload {
+            entities,
+            removed: self.removed_components.clone(),
+            device_discovery: self.config.topic.device_discovery,
+        };
+    }
+
+    /// remove every entity in 'previous' that isn't in 'current' from homeassistant, and clear its retained state.
+    fn remove_stale_entities(&mut self, previous: &EntityListPayload, current: &EntityListPayload) {
+        let current_topics: HashSet<&String> = current
+            .entities
+            .iter()
+            .map(|record| &record.discovery_topic)
+            .collect();
+        let mut removed: HashSet<&String> = HashSet::new();
+
+        // removals an earlier run queued up, but didn't get to publish. Entities that came back aren't removed after all.
+        if self.config.topic.device_discovery {
+            for topic in previous.removed.iter() {
+                if !self.removed_components.contains(topic) {
+                    self.removed_components.push(topic.clone());
+                }
+            }
+        }
+        self.removed_components
+            .retain(|topic| !current_topics.contains(topic));
+
+        for record in previous.entities.iter() {
+            if current_topics.contains(&record.discovery_topic)
+                || !removed.insert(&record.discovery_topic)
+            {
+                continue;
+            }
+            info!(
+                "removing entity that no longer exists: {}",
+                record.discovery_topic
+            );
+            // an empty discovery message removes the entity from homeassistant. Entities that were part of a device discovery message are removed by the next one instead.
+            let discovery_topic = if previous.device_discovery {
+                if !self.removed_components.contains(&record.discovery_topic) {
+                    self.removed_components.push(record.discovery_topic.clone());
+                }
+                None
+            } else {
+                Some(&record.discovery_topic)
+            };
+            let topics = discovery_topic.into_iter().chain(record.state_topic.iter());
+            for topic in topics {
+                let _ = self.client.try_publish(topic, QoS::AtLeastOnce, true, "");
+            }
+        }
+    }
+
+    /// log something that went wrong, and let anyone watching the error topic know about it.
+    fn report_error(&self, err: BridgeError) {
+        error!("{err}");
+        let published =
+            self.client
+                .try_publish(&self.error_topic, QoS::AtLeastOnce, false, err.to_string());
+        match published {
+            Ok(_) => metrics().mqtt_published(&self.error_topic),
+            Err(err) => error!("could not report error on \"{}\": {err}", self.error_topic),
+        }
+    }
+
+    /// Handle commands from homeassistant until the connection fails for good. Every config sent on 'reloads' replaces the running one, with the entities 'entities' makes for it, see apply_config().
+    pub async fn listen<T: 'static + HaMqttEntity>(
+        &mut self,
+        mut reloads: mpsc::UnboundedReceiver<Config>,
+        entities: impl Fn(&Config) -> Vec<T>,
+    ) -> Result<(), Error> {
+        self.subscribe_to_status().await?;
+        if self.config.topic.device_discovery {
+            self.send_all_discovery_messages().await;
+        }
 
         info!("listening for mqtt messages...");
 
-        // Iterate to poll the eventloop for connection progress
+        // handle everything the connection task passes along, and every new config in between.
         let mut error_count = 0;
-        for notification in connection.iter() {
+        loop {
+            let notification = tokio::select! {
+                notification = self.next_notification() => notification,
+                Some(config) = reloads.recv() => {
+                    self.apply_config(config, &entities).await;
+                    continue;
+                },
+            };
+            let Some(notification) = notification else {
+                break;
+            };
             trace!("Notification = {:?}", notification);
             match notification {
+                Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                    self.connected.send_replace(true);
+                    // this happens again after every reconnect, which also replaces our last will.
+                    self.publish_availability("online").await;
+                }
                 Ok(Event::Incoming(Incoming::Publish(event))) => {
+                    metrics().mqtt_received(&event.topic);
                     if event.topic == self.config.topic.status {
                         if event.payload == "online" {
                             debug!("mqtt integration online. resending discovery messages",);
-                            self.send_all_discovery_messages();
+                            self.send_all_discovery_messages().await;
                         } else {
                             debug!("mqtt integration status changed {:?}", event);
                         }
@@ -205,10 +718,13 @@ impl HaBroker {
                         debug!("new event published! {:?}", event);
                         error_count = 0; // reset error count
                                          // find an entity for event.topic, and use that
-                        self.notify_entities(&event);
+                        if let Err(err) = self.notify_entities(&event) {
+                            self.report_error(err);
+                        }
                     }
                 }
                 Err(err) => {
+                    self.connected.send_replace(false);
                     error_count += 1;
                     if error_count > MAX_ERROR_COUNT {
                         error!("Too many successive errors. Killing process.");
@@ -221,4 +737,706 @@ impl HaBroker {
         }
         return Ok(());
     }
+
+    /// subscribe to the homeassistant status topic to recieve birth/will messages. see https://www.home-assistant.io/integrations/mqtt#use-the-birth-and-will-messages-to-trigger-discovery
+    async fn subscribe_to_status(&mut self) -> Result<(), BridgeError> {
+        self.client
+            .subscribe(&self.config.topic.status, QoS::AtLeastOnce)
+            .await
+            .map_err(|source| BridgeError::Subscribe {
+                topic: self.config.topic.status.clone(),
+                source,
+            })?;
+        self.subscriptions += 1;
+        return Ok(());
+    }
+
+    async fn next_notification(&mut self) -> Option<Result<Event, ConnectionError>> {
+        if let Some(notification) = self.pending.pop_front() {
+            return Some(notification);
+        }
+        return self.receive().await;
+    }
+
+    /// the next notification from the connection, counting the subscribe and unsubscribe requests that went out.
+    async fn receive(&mut self) -> Option<Result<Event, ConnectionError>> {
+        let notification = self.notifications.recv().await;
+        match notification {
+            Some(Ok(Event::Outgoing(Outgoing::Subscribe(_)))) => self.subscriptions_sent += 1,
+            Some(Ok(Event::Outgoing(Outgoing::Unsubscribe(_)))) => self.unsubscriptions_sent += 1,
+            _ => {}
+        }
+        return notification;
+    }
+
+    /// unsubscribe from 'topic' without waiting, keeping count of the request if it was queued.
+    fn try_unsubscribe(&mut self, topic: &str) {
+        if self.client.try_unsubscribe(topic).is_ok() {
+            self.unsubscriptions += 1;
+        }
+    }
+
+    async fn publish_availability(&self, availability: &str) {
+        let published = self
+            .client
+            .publish(
+                &self.availability_topic,
+                QoS::AtLeastOnce,
+                true,
+                availability,
+            )
+            .await;
+        match published {
+            Ok(_) => metrics().mqtt_published(&self.availability_topic),
+            Err(err) => error!("could not publish availability \"{availability}\": {err}"),
+        }
+    }
+
+    /// Say goodbye to homeassistant, and close the connection. Everything queued before this is sent to the broker before disconnecting.
+    pub async fn shutdown(mut self) {
+        let clear_state = self.config.shutdown.clear_state;
+        self.say_goodbye(clear_state).await;
+    }
+
+    /// tell homeassistant we are going offline, clearing the retained states if 'clear_state', and disconnect.
+    async fn say_goodbye(&mut self, clear_state: bool) {
+        info!("disconnecting from mqtt...");
+        // stop taking commands first. Acknowledgements only say which packet they are for, so these unsubscribes also mark where our goodbye messages start.
+        let command_topics: Vec<String> = self
+            .entities
+            .values()
+            .filter_map(|entity| entity.get_command_topic())
+            .collect();
+        for topic in command_topics {
+            self.try_unsubscribe(&topic);
+        }
+        // without command topics, the start is an older unsubscribe, and we may also wait for messages queued ahead of ours.
+        let start = self.unsubscriptions;
+
+        self.publish_availability("offline").await;
+        if clear_state {
+            for entity in self.entities.values() {
+                // an empty retained message deletes the retained state.
+                entity.get_state_topic().map(|topic| {
+                    let _ = self.client.try_publish(topic, QoS::AtLeastOnce, true, "");
+                });
+            }
+        }
+
+        self.disconnect(start).await;
+    }
+
+    /// wait for the messages queued since the 'start'th unsubscribe request to be acknowledged, and then close the connection.
+    async fn disconnect(&mut self, start: usize) {
+        // some brokers drop messages that haven't been routed yet when the client disconnects, so wait for them to be acknowledged.
+        // we stop listening for homeassistant restarts here, which also marks where our messages end.
+        self.try_unsubscribe(&self.config.topic.status.clone());
+        let end = self.unsubscriptions;
+        // if that couldn't be queued, nothing else could either, and there is nothing to wait for.
+        let mut unacknowledged = HashSet::new();
+        if end > start {
+            while self.unsubscriptions_sent < end || !unacknowledged.is_empty() {
+                let counted = self.unsubscriptions_sent >= start;
+                match self.receive().await {
+                    // QoS 0 messages have no packet id, and are never acknowledged.
+                    Some(Ok(Event::Outgoing(Outgoing::Publish(pkid)))) if counted && pkid != 0 => {
+                        unacknowledged.insert(pkid);
+                    }
+                    Some(Ok(Event::Incoming(Incoming::PubAck(ack)))) => {
+                        unacknowledged.remove(&ack.pkid);
+                    }
+                    Some(Ok(Event::Incoming(Incoming::PubComp(comp)))) => {
+                        unacknowledged.remove(&comp.pkid);
+                    }
+                    Some(Err(_)) | None => break,
+                    _ => {}
+                }
+            }
+        }
+
+        if let Err(err) = self.client.disconnect().await {
+            error!("could not disconnect from mqtt: {err}");
+            return;
+        }
+        let _ = (&mut self.connection).await;
+    }
+}
+
+/// the discovery messages for 'entities', as topics and payloads. This is what HaBroker publishes, and what --print-discovery prints. With device based discovery, this is one message for the whole device, which also removes the 'removed' discovery topics.
+pub fn discovery_messages<'a, T: HaMqttEntity + ?Sized + 'a>(
+    config: &Config,
+    entities: impl IntoIterator<Item = &'a T>,
+    removed: &[String],
+) -> Vec<(String, String)> {
+    if config.topic.device_discovery {
+        let device = Device::from_config(config);
+        let mut discovery_payload = DeviceDiscoveryPayload::new(&device);
+        for entity in entities {
+            discovery_payload
+                .add_component(&entity.get_discovery_topic(), entity.get_config_payload());
+        }
+        for discovery_topic in removed {
+            discovery_payload.remove_component(discovery_topic);
+        }
+        let payload = serde_json::to_string(&discovery_payload)
+            .expect("could not stringify the device discovery payload");
+        return vec![(device.discovery_topic(), payload)];
+    }
+    return entities
+        .into_iter()
+        .map(|entity| {
+            let payload = serde_json::to_string(&entity.get_config_payload())
+                .expect("could not stringify the discovery payload");
+            (entity.get_discovery_topic(), payload)
+        })
+        .collect();
+}
+
+#[cfg(test)]
+fn start_proxy(config: Config) -> tokio::sync::oneshot::Sender<()> {
+    return start_reloadable_proxy(config).0;
+}
+
+#[cfg(test)]
+fn start_reloadable_proxy(
+    config: Config,
+) -> (
+    tokio::sync::oneshot::Sender<()>,
+    mpsc::UnboundedSender<Config>,
+) {
+    let (shutdown, shutdown_signal) = tokio::sync::oneshot::channel();
+    let (reload, reloads) = mpsc::unbounded_channel();
+    tokio::spawn(crate::bridge::run(
+        config,
+        async move {
+            let _ = shutdown_signal.await;
+        },
+        reloads,
+    ));
+    return (shutdown, reload);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn publishing_discovery_messages() {
+    use crate::test_harness::TestBroker;
+
+    let broker = TestBroker::start();
+    let mut homeassistant = broker.client("homeassistant/#").await;
+    let _shutdown = start_proxy(broker.proxy_config("discovery"));
+
+    let discovery = homeassistant
+        .next_message("homeassistant/switch/discovery_tv/config")
+        .await;
+    let discovery: serde_json::Value =
+        serde_json::from_str(&discovery).expect("discovery payload is not json");
+    assert_eq!(discovery["name"], "tv");
+    assert_eq!(discovery["unique_id"], "discovery_tv");
+    assert_eq!(discovery["device_class"], "switch");
+    assert_eq!(
+        discovery["state_topic"],
+        "homeassistant/switch/discovery_tv/state"
+    );
+    assert_eq!(
+        discovery["command_topic"],
+        "homeassistant/switch/discovery_tv/set"
+    );
+    assert_eq!(discovery["device"]["identifiers"][0], "discovery");
+    assert_eq!(discovery["payload_on"], "ON");
+    assert_eq!(discovery["icon"], "mdi:television");
+    assert_eq!(discovery["payload_off"], "OFF");
+    assert_eq!(
+        discovery["availability_topic"],
+        "homeassistant/discovery/availability"
+    );
+
+    for button in ["volumeup", "volumedown", "mute", "Source1", "Source4"] {
+        let topic = format!("homeassistant/button/discovery_{button}/config");
+        let discovery: serde_json::Value =
+            serde_json::from_str(&homeassistant.next_message(&topic).await)
+                .expect("discovery payload is not json");
+        assert_eq!(
+            discovery["command_topic"],
+            format!("homeassistant/button/discovery_{button}/set")
+        );
+        assert!(discovery.get("state_topic").is_none());
+        assert_eq!(discovery["payload_press"], "PRESS");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resending_discovery_when_homeassistant_restarts() {
+    use crate::test_harness::TestBroker;
+    use std::time::Duration;
+
+    let broker = TestBroker::start();
+    let mut homeassistant = broker.client("homeassistant/#").await;
+    let _shutdown = start_proxy(broker.proxy_config("restart"));
+    homeassistant
+        .next_message("homeassistant/switch/restart_tv/config")
+        .await;
+
+    // we don't know exactly when the proxy subscribes to the status topic, so keep announcing until it answers.
+    let mut resent = false;
+    for _ in 0..10 {
+        homeassistant.publish("homeassistant/status", "online");
+        resent = homeassistant
+            .try_wait_for(
+                "homeassistant/switch/restart_tv/config",
+                |_payload| true,
+                Duration::from_millis(500),
+            )
+            .await
+            .is_some();
+        if resent {
+            break;
+        }
+    }
+    assert!(resent, "discovery messages were not sent again");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn switching_the_tv_on() {
+    use crate::test_harness::TestBroker;
+
+    let broker = TestBroker::start();
+    let mut homeassistant = broker.client("homeassistant/#").await;
+    let _shutdown = start_proxy(broker.proxy_config("command"));
+
+    // the simulated TV starts out in standby, and the proxy polls it as soon as it starts.
+    // by the time this is published, the proxy has already subscribed to the command topic.
+    homeassistant
+        .wait_for("homeassistant/switch/command_tv/state", |state| {
+            state == "OFF"
+        })
+        .await;
+
+    homeassistant.publish("homeassistant/switch/command_tv/set", "ON");
+    homeassistant
+        .wait_for("homeassistant/switch/command_tv/state", |state| {
+            state == "ON"
+        })
+        .await;
+
+    homeassistant.publish("homeassistant/switch/command_tv/set", "TOGGLE");
+    homeassistant
+        .wait_for("homeassistant/switch/command_tv/state", |state| {
+            state == "OFF"
+        })
+        .await;
+
+    homeassistant.publish(
+        "homeassistant/switch/command_tv/set",
+        r#"{"source":"HDMI 2","power":"ON"}"#,
+    );
+    homeassistant
+        .wait_for("homeassistant/switch/command_tv/state", |state| {
+            state == "ON"
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn surviving_malformed_commands() {
+    use crate::test_harness::TestBroker;
+
+    let broker = TestBroker::start();
+    let mut homeassistant = broker.client("homeassistant/#").await;
+    let _shutdown = start_proxy(broker.proxy_config("malformed"));
+    homeassistant
+        .wait_for("homeassistant/switch/malformed_tv/state", |state| {
+            state == "OFF"
+        })
+        .await;
+
+    homeassistant.publish("homeassistant/switch/malformed_tv/set", vec![0xff, 0xfe]);
+    let error = homeassistant
+        .next_message("homeassistant/malformed/error")
+        .await;
+    assert!(
+        error.contains("not valid utf-8"),
+        "unexpected error: {error}"
+    );
+
+    homeassistant.publish("homeassistant/switch/malformed_tv/set", "SIDEWAYS");
+    let error = homeassistant
+        .next_message("homeassistant/malformed/error")
+        .await;
+    assert_eq!(error, "\"SIDEWAYS\" is not a valid command for \"tv\"");
+
+    // and the bridge is still there to take proper commands.
+    homeassistant.publish("homeassistant/switch/malformed_tv/set", "ON");
+    homeassistant
+        .wait_for("homeassistant/switch/malformed_tv/state", |state| {
+            state == "ON"
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn restoring_retained_state() {
+    use crate::config::ReplayConfig;
+    use crate::test_harness::TestBroker;
+
+    let broker = TestBroker::start();
+    let mut homeassistant = broker.client("homeassistant/#").await;
+
+    // leftovers from the last run, which had an entity we don't have anymore.
+    homeassistant.publish_retained("homeassistant/switch/restore_tv/state", "ON");
+    homeassistant.publish_retained(
+        "homeassistant/restore/entities",
+        r#"{"entities":[{"discovery_topic":"homeassistant/button/restore_gone/config","state_topic":"homeassistant/button/restore_gone/state"}]}"#,
+    );
+
+    // replaying an empty capture means the TV never tells us its state, so all we know is what we restored.
+    let capture = crate::test_harness::temp_path("restoring_retained_state.cap");
+    std::fs::write(&capture, "").expect("could not write capture");
+    let mut config = broker.proxy_config("restore");
+    config.mqtt.retain_state = true;
+    config.cec.simulator = None;
+    config.cec.replay = Some(ReplayConfig {
+        file: capture.to_str().expect("temp dir is not utf-8").to_string(),
+        speed: 1.0,
+    });
+    let _shutdown = start_proxy(config);
+
+    homeassistant
+        .wait_for("homeassistant/button/restore_gone/config", |config| {
+            config.is_empty()
+        })
+        .await;
+    homeassistant
+        .wait_for("homeassistant/restore/entities", |entities| {
+            entities.contains("homeassistant/switch/restore_tv/config")
+        })
+        .await;
+
+    // the TV was on last time, so toggling it switches it off.
+    homeassistant.publish("homeassistant/switch/restore_tv/set", "TOGGLE");
+    homeassistant
+        .wait_for("homeassistant/switch/restore_tv/state", |state| {
+            state == "OFF"
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cleaning_up_after_changing_ids() {
+    use crate::test_harness::TestBroker;
+
+    let broker = TestBroker::start();
+    let mut homeassistant = broker.client("homeassistant/#").await;
+    let state_file = crate::test_harness::temp_path("cleaning_up_after_changing_ids.json");
+    let _ = std::fs::remove_file(&state_file);
+    let state_file = state_file.to_str().expect("temp dir is not utf-8");
+
+    let mut config = broker.proxy_config("before");
+    config.device.state_file = Some(state_file.to_string());
+    let shutdown = start_proxy(config);
+    homeassistant
+        .wait_for("homeassistant/before/entities", |entities| {
+            entities.contains("homeassistant/switch/before_tv/config")
+        })
+        .await;
+    shutdown.send(()).expect("proxy already stopped");
+    homeassistant
+        .wait_for("homeassistant/before/availability", |availability| {
+            availability == "offline"
+        })
+        .await;
+
+    // the retained list is under the old id, so only the local file knows about the old entities.
+    let mut config = broker.proxy_config("after");
+    config.device.state_file = Some(state_file.to_string());
+    let _shutdown = start_proxy(config);
+    homeassistant
+        .wait_for_each(
+            &[
+                "homeassistant/switch/before_tv/config",
+                "homeassistant/button/before_mute/config",
+            ],
+            |config| config.is_empty(),
+        )
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn purging_everything() {
+    use crate::test_harness::TestBroker;
+
+    let broker = TestBroker::start();
+    let mut homeassistant = broker.client("homeassistant/#").await;
+    let shutdown = start_proxy(broker.proxy_config("purge"));
+    homeassistant
+        .wait_for("homeassistant/purge/entities", |entities| {
+            entities.contains("homeassistant/switch/purge_tv/config")
+        })
+        .await;
+    shutdown.send(()).expect("proxy already stopped");
+    homeassistant
+        .wait_for("homeassistant/purge/availability", |availability| {
+            availability == "offline"
+        })
+        .await;
+
+    crate::bridge::purge(broker.proxy_config("purge"))
+        .await
+        .expect("could not purge");
+    homeassistant
+        .wait_for_each(
+            &[
+                "homeassistant/switch/purge_tv/config",
+                "homeassistant/switch/purge_tv/state",
+                "homeassistant/button/purge_Source4/config",
+                "homeassistant/purge/entities",
+                "homeassistant/purge/availability",
+            ],
+            |payload| payload.is_empty(),
+        )
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn monitor_mode_discovery() {
+    use crate::test_harness::TestBroker;
+
+    let broker = TestBroker::start();
+    let mut homeassistant = broker.client("homeassistant/#").await;
+    let mut config = broker.proxy_config("monitor");
+    config.cec.monitor = true;
+    let _shutdown = start_proxy(config);
+
+    let discovery = homeassistant
+        .next_message("homeassistant/binary_sensor/monitor_tv/config")
+        .await;
+    let discovery: serde_json::Value =
+        serde_json::from_str(&discovery).expect("discovery payload is not json");
+    assert_eq!(discovery["device_class"], "power");
+    assert!(discovery.get("command_topic").is_none());
+
+    homeassistant
+        .next_message("homeassistant/sensor/monitor_source/config")
+        .await;
+    let discovery = homeassistant
+        .next_message("homeassistant/sensor/monitor_traffic/config")
+        .await;
+    let discovery: serde_json::Value =
+        serde_json::from_str(&discovery).expect("discovery payload is not json");
+    assert_eq!(discovery["entity_category"], "diagnostic");
+    assert_eq!(discovery["enabled_by_default"], false);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn publishing_bus_diagnostics() {
+    use crate::test_harness::TestBroker;
+
+    let broker = TestBroker::start();
+    let mut homeassistant = broker.client("homeassistant/#").await;
+    let _shutdown = start_proxy(broker.proxy_config("diagnostics"));
+
+    let discovery = homeassistant
+        .next_message("homeassistant/binary_sensor/diagnostics_cec_connection/config")
+        .await;
+    let discovery: serde_json::Value =
+        serde_json::from_str(&discovery).expect("discovery payload is not json");
+    assert_eq!(discovery["device_class"], "connectivity");
+    assert_eq!(discovery["entity_category"], "diagnostic");
+
+    homeassistant
+        .wait_for(
+            "homeassistant/binary_sensor/diagnostics_cec_connection/state",
+            |state| state == "ON",
+        )
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn device_based_discovery() {
+    use crate::test_harness::TestBroker;
+
+    let broker = TestBroker::start();
+    let mut homeassistant = broker.client("homeassistant/#").await;
+
+    // the last run announced an entity we don't have anymore, as part of the device, and didn't get to remove another one.
+    homeassistant.publish_retained(
+        "homeassistant/device/entities",
+        r#"{"entities":[{"discovery_topic":"homeassistant/button/device_gone/config"}],"device_discovery":true,"removed":["homeassistant/sensor/device_older/config"]}"#,
+    );
+    let mut config = broker.proxy_config("device");
+    config.topic.device_discovery = true;
+    let _shutdown = start_proxy(config);
+
+    let discovery = homeassistant
+        .next_message("homeassistant/device/device/config")
+        .await;
+    let discovery: serde_json::Value =
+        serde_json::from_str(&discovery).expect("discovery payload is not json");
+    assert_eq!(discovery["device"]["identifiers"][0], "device");
+    let components = &discovery["components"];
+    assert_eq!(components["device_tv"]["platform"], "switch");
+    assert_eq!(
+        components["device_tv"]["command_topic"],
+        "homeassistant/switch/device_tv/set"
+    );
+    assert!(components["device_tv"].get("device").is_none());
+    assert_eq!(components["device_mute"]["platform"], "button");
+    assert_eq!(
+        components["device_gone"],
+        serde_json::json!({"platform": "button"})
+    );
+    assert_eq!(
+        components["device_older"],
+        serde_json::json!({"platform": "sensor"})
+    );
+
+    homeassistant.publish("homeassistant/switch/device_tv/set", "ON");
+    homeassistant
+        .wait_for("homeassistant/switch/device_tv/state", |state| {
+            state == "ON"
+        })
+        .await;
+
+    // once the removals went out, there is nothing left to remove.
+    broker
+        .client("homeassistant/device/entities")
+        .await
+        .wait_for("homeassistant/device/entities", |list| {
+            !list.contains("removed")
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn running_macros_from_homeassistant() {
+    use crate::test_harness::TestBroker;
+
+    let broker = TestBroker::start();
+    let mut homeassistant = broker.client("homeassistant/#").await;
+    let mut config = broker.proxy_config("macros");
+    let movie_night = toml::from_str(
+        r#"
+        id = "movie_night"
+        name = "Movie Night"
+        platform = "scene"
+        steps = [{ power = "on" }, { source = 2 }]
+        "#,
+    )
+    .expect("invalid macro");
+    config.macros = vec![movie_night];
+    let _shutdown = start_proxy(config);
+
+    let discovery = homeassistant
+        .next_message("homeassistant/scene/macros_macro_movie_night/config")
+        .await;
+    let discovery: serde_json::Value =
+        serde_json::from_str(&discovery).expect("discovery payload is not json");
+    assert_eq!(discovery["name"], "Movie Night");
+    assert_eq!(discovery["payload_on"], "ON");
+    assert!(discovery.get("payload_off").is_none());
+
+    homeassistant.publish("homeassistant/scene/macros_macro_movie_night/set", "ON");
+    homeassistant
+        .wait_for(
+            "homeassistant/sensor/macros_macro_movie_night_status/state",
+            |status| status == "done",
+        )
+        .await;
+    homeassistant
+        .wait_for("homeassistant/switch/macros_tv/state", |state| {
+            state == "ON"
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reloading_the_config() {
+    use crate::test_harness::TestBroker;
+
+    let broker = TestBroker::start();
+    let mut homeassistant = broker.client("homeassistant/#").await;
+    let config = broker.proxy_config("reload");
+    let (_shutdown, reload) = start_reloadable_proxy(config.clone());
+    homeassistant
+        .wait_for("homeassistant/reload/entities", |entities| {
+            entities.contains("homeassistant/switch/reload_tv/config")
+        })
+        .await;
+
+    // the same connection moves over to device based discovery.
+    let mut reloaded = config.clone();
+    reloaded.topic.device_discovery = true;
+    reload.send(reloaded).expect("proxy already stopped");
+    homeassistant
+        .wait_for("homeassistant/switch/reload_tv/config", |config| {
+            config.contains("migrate_discovery")
+        })
+        .await;
+    let discovery = homeassistant
+        .next_message("homeassistant/device/reload/config")
+        .await;
+    assert!(discovery.contains("reload_tv"));
+    homeassistant.publish("homeassistant/switch/reload_tv/set", "ON");
+    homeassistant
+        .wait_for("homeassistant/switch/reload_tv/state", |state| {
+            state == "ON"
+        })
+        .await;
+
+    // a new id needs a new connection, and takes the old device offline.
+    let mut renamed = config.clone();
+    renamed.device.unique_id = "renamed".to_string();
+    reload.send(renamed).expect("proxy already stopped");
+    homeassistant
+        .wait_for("homeassistant/reload/availability", |availability| {
+            availability == "offline"
+        })
+        .await;
+    homeassistant
+        .wait_for("homeassistant/renamed/availability", |availability| {
+            availability == "online"
+        })
+        .await;
+    homeassistant
+        .wait_for_each(
+            &[
+                "homeassistant/switch/renamed_tv/config",
+                "homeassistant/button/renamed_mute/config",
+            ],
+            |config| !config.is_empty(),
+        )
+        .await;
+    homeassistant.publish("homeassistant/switch/renamed_tv/set", "OFF");
+    homeassistant
+        .wait_for("homeassistant/switch/renamed_tv/state", |state| {
+            state == "OFF"
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shutting_down_cleanly() {
+    use crate::test_harness::TestBroker;
+
+    let broker = TestBroker::start();
+    let mut homeassistant = broker.client("homeassistant/#").await;
+    let mut config = broker.proxy_config("shutdown");
+    config.shutdown.clear_state = true;
+    let shutdown = start_proxy(config);
+
+    homeassistant
+        .wait_for("homeassistant/shutdown/availability", |availability| {
+            availability == "online"
+        })
+        .await;
+
+    shutdown.send(()).expect("proxy already stopped");
+    homeassistant
+        .wait_for("homeassistant/shutdown/availability", |availability| {
+            availability == "offline"
+        })
+        .await;
+    homeassistant
+        .wait_for("homeassistant/switch/shutdown_tv/state", |state| {
+            state.is_empty()
+        })
+        .await;
 }
@@ -0,0 +1,231 @@
+//! Helpers for end to end tests: an MQTT broker running inside the test process, a client to watch what the proxy publishes, and a config that points the proxy at both, with a simulated CEC bus.
+use std::{
+    collections::HashMap,
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rumqttc::{Client, Event, Incoming, MqttOptions, Publish, QoS};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::Config;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// how many test clients we have connected, so each gets its own client id. The broker drops the older connection when two share one.
+static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// a file in the temp dir named after the test using it, and this process, so tests running at the same time never share one.
+pub fn temp_path(name: &str) -> PathBuf {
+    return std::env::temp_dir().join(format!("hdmicec2mqtt_{}_{name}", std::process::id()));
+}
+
+/// An MQTT broker on localhost, listening on a port nobody else is using.
+pub struct TestBroker {
+    pub port: u16,
+}
+
+impl TestBroker {
+    pub fn start() -> Self {
+        // ask the OS for a free port, and then give it to the broker.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("could not find a free port")
+            .port();
+
+        let config: rumqttd::Config = toml::from_str(&format!(
+            r#"
+            id = 0
+
+            [router]
+            max_connections = 10
+            max_outgoing_packet_count = 200
+            max_segment_size = 104857600
+            max_segment_count = 10
+
+            [v4.1]
+            name = "v4-1"
+            listen = "127.0.0.1:{port}"
+            next_connection_delay_ms = 1
+            [v4.1.connections]
+            connection_timeout_ms = 60000
+            max_payload_size = 20480
+            max_inflight_count = 100
+            dynamic_filters = true
+            "#
+        ))
+        .expect("invalid broker config");
+
+        thread::spawn(move || {
+            rumqttd::Broker::new(config)
+                .start()
+                .expect("could not start broker");
+        });
+
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(started.elapsed() < TIMEOUT, "broker did not start");
+            thread::sleep(Duration::from_millis(10));
+        }
+        return Self { port };
+    }
+
+    /// a config for the proxy that connects to this broker, and talks to a simulated CEC bus. 'id' is used as the device's unique_id.
+    pub fn proxy_config(&self, id: &str) -> Config {
+        return toml::from_str(&format!(
+            r#"
+            [mqtt]
+            host = "127.0.0.1"
+            port = {}
+            deviceid = "{id}_proxy"
+
+            [topic]
+
+            [device]
+            unique_id = "{id}"
+
+            [cec.simulator]
+            "#,
+            self.port
+        ))
+        .expect("invalid proxy config");
+    }
+
+    /// connect a client that subscribes to 'filter', and records everything published to it.
+    pub async fn client(&self, filter: &str) -> TestClient {
+        let id = CLIENTS.fetch_add(1, Ordering::SeqCst);
+        let options = MqttOptions::new(
+            format!("test_client_{}_{id}", self.port),
+            "127.0.0.1",
+            self.port,
+        );
+        let (client, mut connection) = Client::new(options, 50);
+        client
+            .subscribe(filter, QoS::AtLeastOnce)
+            .expect("could not subscribe");
+
+        // the connection is driven by its own thread, and hands everything over without blocking the tests' runtime.
+        let (subscribed, subscribed_receiver) = oneshot::channel();
+        let mut subscribed = Some(subscribed);
+        let (sender, messages) = mpsc::unbounded_channel();
+        thread::spawn(move || {
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Incoming(Incoming::SubAck(_))) => {
+                        subscribed.take().map(|subscribed| subscribed.send(()));
+                    }
+                    Ok(Event::Incoming(Incoming::Publish(publish))) => match sender.send(publish) {
+                        Ok(_) => {}
+                        Err(_) => return,
+                    },
+                    Err(_) => return,
+                    _ => {}
+                }
+            }
+        });
+        tokio::time::timeout(TIMEOUT, subscribed_receiver)
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .expect("could not subscribe to test topics");
+
+        return TestClient {
+            client,
+            messages,
+            seen: HashMap::new(),
+        };
+    }
+}
+
+/// An MQTT client for poking at the proxy from the outside, like homeassistant would.
+pub struct TestClient {
+    client: Client,
+    messages: mpsc::UnboundedReceiver<Publish>,
+    /// every payload seen so far, by topic. Useful for working out why a test timed out.
+    seen: HashMap<String, Vec<String>>,
+}
+
+impl TestClient {
+    pub fn publish<P: Into<Vec<u8>>>(&self, topic: &str, payload: P) {
+        self.client
+            .publish(topic, QoS::AtLeastOnce, false, payload)
+            .expect("could not publish");
+    }
+
+    pub fn publish_retained(&self, topic: &str, payload: &str) {
+        self.client
+            .publish(topic, QoS::AtLeastOnce, true, payload)
+            .expect("could not publish");
+    }
+
+    /// wait for the next message on 'topic', and return its payload.
+    pub async fn next_message(&mut self, topic: &str) -> String {
+        return self.wait_for(topic, |_payload| true).await;
+    }
+
+    /// wait for a message on 'topic' that matches 'predicate', and return its payload.
+    pub async fn wait_for<F: Fn(&str) -> bool>(&mut self, topic: &str, predicate: F) -> String {
+        return self
+            .try_wait_for(topic, predicate, TIMEOUT)
+            .await
+            .unwrap_or_else(|| {
+                panic!(
+                    "nothing published to {topic} in time. saw messages on {:?}",
+                    self.seen
+                )
+            });
+    }
+
+    /// wait for a message matching 'predicate' on every one of 'topics', in any order.
+    pub async fn wait_for_each<F: Fn(&str) -> bool>(&mut self, topics: &[&str], predicate: F) {
+        let mut remaining: Vec<&str> = topics.to_vec();
+        let deadline = tokio::time::Instant::now() + TIMEOUT;
+        while !remaining.is_empty() {
+            let publish = tokio::time::timeout_at(deadline, self.messages.recv())
+                .await
+                .ok()
+                .flatten()
+                .unwrap_or_else(|| {
+                    panic!(
+                        "nothing published to {remaining:?} in time. saw messages on {:?}",
+                        self.seen
+                    )
+                });
+            let payload = String::from_utf8_lossy(&publish.payload).to_string();
+            self.seen
+                .entry(publish.topic.clone())
+                .or_default()
+                .push(payload.clone());
+            if predicate(&payload) {
+                remaining.retain(|topic| *topic != publish.topic);
+            }
+        }
+    }
+
+    /// like wait_for(), but gives up after 'timeout' instead of panicking.
+    pub async fn try_wait_for<F: Fn(&str) -> bool>(
+        &mut self,
+        topic: &str,
+        predicate: F,
+        timeout: Duration,
+    ) -> Option<String> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let publish = tokio::time::timeout_at(deadline, self.messages.recv())
+                .await
+                .ok()??;
+            // we publish broken payloads on purpose in some tests, so don't choke on them here.
+            let payload = String::from_utf8_lossy(&publish.payload).to_string();
+            self.seen
+                .entry(publish.topic.clone())
+                .or_default()
+                .push(payload.clone());
+            if publish.topic == topic && predicate(&payload) {
+                return Some(payload);
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! A live stream of everything happening on the bus, as JSON over a WebSocket. It is fed from the same events as the MQTT entities, so it's handy for debugging, or for a dashboard of your own.
+use std::sync::Arc;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    response::Response,
+    routing::get,
+    Router,
+};
+use log::{debug, warn};
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+
+use crate::cec_frame::Direction;
+use crate::hdmicec_entity::{CecEvent, HdmiCecProcess};
+
+/// The /events route. It only ever reads from the bus, so it is safe to serve in monitor mode too.
+pub fn router(hdmicec: Arc<HdmiCecProcess>) -> Router {
+    return Router::new()
+        .route("/events", get(events))
+        .with_state(hdmicec);
+}
+
+async fn events(State(hdmicec): State<Arc<HdmiCecProcess>>, upgrade: WebSocketUpgrade) -> Response {
+    // subscribe before the upgrade, so nothing is missed between the handshake and the first event.
+    let events = hdmicec.subscribe();
+    return upgrade.on_upgrade(move |socket| stream_events(socket, events));
+}
+
+/// send every event to the client, until it goes away.
+async fn stream_events(mut socket: WebSocket, mut events: broadcast::Receiver<CecEvent>) {
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            message = socket.recv() => match message {
+                // anything the client sends is ignored, besides closing the socket.
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        };
+        match event {
+            Ok(event) => {
+                let message = event_message(&event).to_string();
+                if socket.send(Message::Text(message.into())).await.is_err() {
+                    break;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("websocket client is too slow, and missed {missed} events");
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+    debug!("websocket client went away");
+}
+
+/// an event as JSON, with a "type" to tell them apart.
+fn event_message(event: &CecEvent) -> Value {
+    return match event {
+        CecEvent::Power(state) => json!({"type": "power", "state": state}),
+        CecEvent::ActiveSource(source) => json!({"type": "active_source", "source": source}),
+        CecEvent::Traffic(frame) => json!({
+            "type": "traffic",
+            "direction": match frame.direction {
+                Direction::Sent => "sent",
+                Direction::Received => "received",
+            },
+            "initiator": frame.initiator,
+            "destination": frame.destination,
+            "opcode": frame.opcode_name(),
+            "frame": frame.to_hex(),
+            "description": frame.to_string(),
+        }),
+        CecEvent::Command { entity, error } => json!({
+            "type": "command",
+            "entity": entity,
+            "error": error,
+        }),
+    };
+}
+
+#[test]
+fn describing_events() {
+    use crate::cec_frame::CecFrame;
+
+    let frame = CecFrame::parse_traffic("TRAFFIC: [  4125]\t>> 0f:36").expect("no frame");
+    let message = event_message(&CecEvent::Traffic(frame));
+    assert_eq!(message["type"], "traffic");
+    assert_eq!(message["direction"], "received");
+    assert_eq!(message["destination"], 15);
+    assert_eq!(message["opcode"], "Standby");
+    assert_eq!(message["frame"], "0f:36");
+
+    let message = event_message(&CecEvent::Command {
+        entity: "mute".to_string(),
+        error: None,
+    });
+    assert_eq!(
+        message,
+        json!({"type": "command", "entity": "mute", "error": null})
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn streaming_events() {
+    use crate::config::{CecConfig, SimulatorConfig};
+    use crate::http::serve;
+    use futures_util::StreamExt;
+    use tokio::net::TcpListener;
+
+    let hdmicec = Arc::new(
+        HdmiCecProcess::new(&CecConfig {
+            simulator: Some(SimulatorConfig::default()),
+            ..CecConfig::default()
+        })
+        .expect("could not start simulator"),
+    );
+    hdmicec.listen().expect("could not listen");
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("could not bind");
+    let address = listener.local_addr().expect("no local address");
+    tokio::spawn(serve(listener, router(hdmicec.clone())));
+
+    let (mut client, _response) =
+        tokio_tungstenite::connect_async(format!("ws://{address}/events"))
+            .await
+            .expect("could not connect");
+    hdmicec.report_command("mute", &Ok(()));
+    hdmicec.update_state(true);
+
+    // the simulator has traffic of its own, so look past it.
+    let mut received = vec![];
+    while received.len() < 2 {
+        let message = tokio::time::timeout(std::time::Duration::from_secs(5), client.next())
+            .await
+            .expect("no event in time")
+            .expect("stream ended")
+            .expect("invalid message");
+        let event: Value =
+            serde_json::from_str(message.to_text().expect("not text")).expect("event is not json");
+        if event["type"] != "traffic" {
+            received.push(event);
+        }
+    }
+    assert_eq!(
+        received[0],
+        json!({"type": "command", "entity": "mute", "error": null})
+    );
+    assert_eq!(received[1], json!({"type": "power", "state": "ON"}));
+}